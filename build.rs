@@ -0,0 +1,58 @@
+// Detects which relevant language features the compiler supports, so that lib.rs can pick the
+// simplest expansion that works.
+//
+// - named_block_from_fn: `core::iter::from_fn` (Rust 1.34)
+// - named_block_control_flow: `core::ops::ControlFlow` (Rust 1.55)
+// - named_block_exit_code: `std::process::ExitCode` (Rust 1.61)
+// - named_block_label_break: `break 'label value` out of a labeled block (Rust 1.65)
+
+use std::env;
+use std::process::Command;
+
+fn rustc_minor_version() -> Option<u32> {
+    // (no `?` on Option here, so that this builds on the old compilers it is meant to detect)
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = match Command::new(rustc).arg("--version").output() {
+        Ok(output) => output,
+        Err(_) => return None,
+    };
+    let version = match String::from_utf8(output.stdout) {
+        Ok(version) => version,
+        Err(_) => return None,
+    };
+
+    // "rustc 1.65.0 (897e37553 2022-11-02)"
+    let mut pieces = version.split_whitespace().nth(1).unwrap_or("").split('.');
+    if pieces.next() != Some("1") {
+        return None;
+    }
+    pieces.next().and_then(|minor| minor.parse().ok())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(named_block_from_fn)");
+    println!("cargo:rustc-check-cfg=cfg(named_block_control_flow)");
+    println!("cargo:rustc-check-cfg=cfg(named_block_exit_code)");
+    println!("cargo:rustc-check-cfg=cfg(named_block_label_break)");
+
+    // if the version can't be determined, fall back to what works on every supported compiler
+    // (Rust 1.30 and later)
+    let minor = match rustc_minor_version() {
+        Some(minor) => minor,
+        None => return,
+    };
+
+    if minor >= 34 {
+        println!("cargo:rustc-cfg=named_block_from_fn");
+    }
+    if minor >= 55 {
+        println!("cargo:rustc-cfg=named_block_control_flow");
+    }
+    if minor >= 61 {
+        println!("cargo:rustc-cfg=named_block_exit_code");
+    }
+    if minor >= 65 {
+        println!("cargo:rustc-cfg=named_block_label_break");
+    }
+}
@@ -0,0 +1,1406 @@
+// Control-flow macros built on top of `block!`.
+//
+// Each one rearranges its input into a call to `block!`, which does the actual work of finding and
+// rewriting breaks. Some of them also walk the code first with a rewriter of their own (for `goto`
+// or `fallthrough`), and `try_block!` needs a couple of hidden items at runtime.
+
+/// A loop that matches on a state value in every iteration.
+///
+/// ```text
+/// loop_match!('label: match INIT { ARMS })
+/// ```
+///
+/// The state starts out as `INIT`, and each iteration matches it against the arms. An arm can go
+/// on to the next iteration with a new state using `continue 'label NEW_STATE`, or leave the loop
+/// with a value using `break 'label VALUE`. This is the same as
+/// `block!('label: loop (state = INIT) { match state { ARMS } })`.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// enum State { Start, Digits(u32), Done(u32) }
+///
+/// let input = b"42x";
+/// let mut pos = 0;
+/// let n = loop_match!('a: match State::Start {
+///     State::Start => continue 'a State::Digits(0),
+///     State::Digits(n) => match input.get(pos) {
+///         Some(&c) if c.is_ascii_digit() => {
+///             pos += 1;
+///             continue 'a State::Digits(n * 10 + u32::from(c - b'0'))
+///         }
+///         _ => continue 'a State::Done(n),
+///     },
+///     State::Done(n) => break 'a n,
+/// });
+/// assert_eq!(n, 42);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! loop_match {
+    ($life:tt: match $($rest:tt)*) => {
+        $crate::__named_block_loop_match!($life () $($rest)*)
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonMatchInLoopMatch)
+    };
+}
+
+/// Implementation detail of `loop_match!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_loop_match {
+    // the arms are the last token tree, everything before them is the initial state
+    ($life:tt ($($init:tt)*) { $($arms:tt)* }) => {
+        $crate::block!($life: loop (state = $($init)*) {
+            match state { $($arms)* }
+        })
+    };
+    ($life:tt ($($init:tt)*) $t:tt $($tail:tt)*) => {
+        $crate::__named_block_loop_match!($life ($($init)* $t) $($tail)*)
+    };
+    ($life:tt $init:tt) => {
+        $crate::__named_block_internal!(@error ExpectedArmsInLoopMatch)
+    };
+}
+
+/// A state machine with named states.
+///
+/// ```text
+/// state_machine!('exit: {
+///     'first: { ... }
+///     'second: { ... }
+///     ...
+/// })
+/// ```
+///
+/// The machine starts in the first state. In a state's body, `goto 'state` moves to another state,
+/// and `break 'exit VALUE` stops the machine with a value. A body that runs to the end moves on to
+/// the next state, and the value of the last state's body is the value of the machine.
+///
+/// This is lowered onto `block!('exit: loop (...) { ... })`, with a number for each state. A
+/// `goto` inside a nested `state_machine!` or `block!` call belongs to that call, and a `goto` to
+/// a state that doesn't exist is rejected with `UnknownStateInGoto`:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = state_machine!('exit: {
+///     'start: { goto 'finish; }
+///     'end: { 1 }
+/// });
+/// # }
+/// ```
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "1000"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let input = "  -12";
+/// let mut chars = input.chars().peekable();
+/// let (mut sign, mut n) = (1, 0);
+/// let value = state_machine!('done: {
+///     'space: {
+///         while chars.peek() == Some(&' ') { chars.next(); }
+///     }
+///     'sign: {
+///         if chars.peek() == Some(&'-') { chars.next(); sign = -1; }
+///     }
+///     'digits: {
+///         match chars.next().and_then(|c| c.to_digit(10)) {
+///             Some(d) => { n = n * 10 + d as i32; goto 'digits; }
+///             None => break 'done sign * n,
+///         }
+///     }
+/// });
+/// assert_eq!(value, -12);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! state_machine {
+    ($exit:lifetime: { $($states:tt)+ }) => {
+        $crate::__named_block_state_machine!(@parse ($exit state) () () (1) $($states)+)
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonStatesInStateMachine)
+    };
+}
+
+/// Implementation detail of `state_machine!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_state_machine {
+    // Numbers the states (1, 1 + 1, ...) and builds a table of (LABEL REPLACEMENT) for goto, and a
+    // chain of `if` statements that runs the current state's body. A body that runs to the end
+    // continues with the next state, except for the last one, which breaks with its value.
+    // (`state` is created once, in the entry rule, so that all of its uses have the same hygiene.)
+    (@parse ($exit:tt $state:ident) ($($table:tt)*) ($($chain:tt)*) ($($n:tt)*) $name:lifetime: { $($body:tt)* } $($rest:tt)+) => {
+        $crate::__named_block_state_machine!(@parse ($exit $state)
+            ($($table)* ($name (continue $exit $($n)*)))
+            ($($chain)*
+                #[allow(unreachable_code)]
+                if $state == $($n)* {
+                    { $($body)* }
+                    continue $exit $($n)* + 1;
+                })
+            ($($n)* + 1)
+            $($rest)+)
+    };
+    (@parse ($exit:tt $state:ident) ($($table:tt)*) ($($chain:tt)*) ($($n:tt)*) $name:lifetime: { $($body:tt)* }) => {
+        $crate::__named_block_goto!(@rewrite
+            (($($table)* ($name (continue $exit $($n)*))) (($crate::__named_block_state_machine!) (@finish $exit $state)))
+            {} ($($chain)* #[allow(unreachable_code)] { break $exit { $($body)* }; }) -> () ())
+    };
+    (@parse $names:tt $table:tt $chain:tt $n:tt $($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesForStateInStateMachine)
+    };
+
+    // the gotos have been rewritten: start the loop in the first state
+    ((@finish $exit:tt $state:ident) { $($body:tt)* }) => {
+        $crate::block!($exit: loop ($state = 1) { $($body)* })
+    };
+
+    // The sections of a `block!` (see @sections in __named_block_internal!) are states too, but
+    // `goto` only jumps forward, so each one is walked on its own. @number gives each section
+    // (NAME (CODE)) a number, and a goto to each later section as a table entry. The first
+    // section is the code before any label, so its name is `()` and it can't be a target.
+    (@number $life:tt $cb:tt $table:tt ($($list:tt)*) ($($n:tt)*) (() $code:tt) $($rest:tt)*) => {
+        $crate::__named_block_state_machine!(@number $life $cb $table
+            ($($list)* (() ($($n)*) $code)) ($($n)* + 1) $($rest)*)
+    };
+    (@number $life:tt $cb:tt ($($table:tt)*) ($($list:tt)*) ($($n:tt)*) ($name:tt $code:tt) $($rest:tt)*) => {
+        $crate::__named_block_state_machine!(@number $life $cb ($($table)* ($name (continue $life $($n)*)))
+            ($($list)* ((($name ($crate::__named_block_internal!(@error BackwardGotoInNamedBlock)))) ($($n)*) $code))
+            ($($n)* + 1) $($rest)*)
+    };
+    (@number $life:tt $cb:tt $table:tt $list:tt $n:tt) => {
+        $crate::__named_block_state_machine!(@section $life state $cb $table () () $list)
+    };
+
+    // Walks one section, with (BACK NUMBER CODE) from @number. The table has the sections up to
+    // this one first, turning gotos to them into an error, then the rest. The chain is the same
+    // as for a state machine.
+    (@section $life:tt $state:ident $cb:tt ($($table:tt)*) ($($back:tt)*) $chain:tt ((($($here:tt)*) $n:tt $code:tt) $($rest:tt)*)) => {
+        $crate::__named_block_goto!(@rewrite
+            (($($back)* $($here)* $($table)*)
+             (($crate::__named_block_state_machine!) (@section_done $life $state $cb ($($table)*) ($($back)* $($here)*) $chain $n ($($rest)*))))
+            {} $code -> () ())
+    };
+    ((@section_done $life:tt $state:ident $cb:tt $table:tt $back:tt ($($chain:tt)*) ($($n:tt)*) ($next:tt $($rest:tt)*)) { $($code:tt)* }) => {
+        $crate::__named_block_state_machine!(@section $life $state $cb $table $back
+            ($($chain)*
+                #[allow(unreachable_code)]
+                if $state == $($n)* {
+                    { $($code)* }
+                    continue $life $($n)* + 1;
+                })
+            ($next $($rest)*))
+    };
+    ((@section_done $life:tt $state:ident () $table:tt $back:tt ($($chain:tt)*) $n:tt ()) { $($code:tt)* }) => {
+        $crate::block!($life: loop ($state = 1) {
+            $($chain)* #[allow(unreachable_code)] { break $life { $($code)* }; }
+        })
+    };
+    ((@section_done $life:tt $state:ident (($($mac:tt)*) $extra:tt) $table:tt $back:tt ($($chain:tt)*) $n:tt ()) { $($code:tt)* }) => {
+        $crate::block!(@callback ($($mac)*) $extra $life: loop ($state = 1) {
+            $($chain)* #[allow(unreachable_code)] { break $life { $($code)* }; }
+        })
+    };
+}
+
+/// Implementation detail of `state_machine!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_goto {
+    // Rewriter for the tree walker (see walk.rs). STATE is (TABLE CALLBACK): `goto 'label` is
+    // looked up in TABLE, a list of (LABEL (REPLACEMENT)), and the first match is replaced. When
+    // the walk is done, the result goes to CALLBACK, which is (MACRO! EXTRA) as in block!'s
+    // @callback form.
+    (@rewrite $st:tt $paren:tt (goto $target:lifetime $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_goto!(@lookup $target $st $st $paren ($($tail)*) -> $out $stack)
+    };
+    // nested state machines and blocks have their own states and sections
+    (@rewrite $st:tt $paren:tt (state_machine ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_goto!(@rewrite $st $paren ($($tail)*) -> ($($out)* state_machine ! $args) $stack)
+    };
+    (@rewrite $st:tt $paren:tt (block ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_goto!(@rewrite $st $paren ($($tail)*) -> ($($out)* block ! $args) $stack)
+    };
+    (@rewrite $st:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_goto!) $st $paren $input -> $out $stack)
+    };
+    (@skip $st:tt $kind:tt $input:tt ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@done ($table:tt (($($mac:tt)*) $extra:tt)) ($($out:tt)*)) => {
+        $($mac)* ($extra { $($out)* })
+    };
+
+    // finds the replacement for a goto (the second argument is what's left of the table to search)
+    (@lookup $target:tt (() $cb:tt) $st:tt $paren:tt $tail:tt -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@error UnknownStateInGoto)
+    };
+    (@lookup $target:tt ((($label:tt ($($rep:tt)*)) $($rest:tt)*) $cb:tt) $st:tt $paren:tt $tail:tt -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $target == $label {
+                $crate::__named_block_goto!(@rewrite $st $paren $tail -> ($($out)* $($rep)*) $stack)
+            } else {
+                $crate::__named_block_goto!(@lookup $target (($($rest)*) $cb) $st $paren $tail -> ($($out)*) $stack)
+            }
+        }
+    };
+}
+
+/// A `switch` statement with explicit fallthrough.
+///
+/// ```text
+/// switch!('switch: match EXPR {
+///     PAT => 'first: { ... }
+///     PAT => 'second: { ... }
+///     ...
+/// })
+/// ```
+///
+/// `EXPR` is matched against the patterns, and the body of the first arm that matches is run. An
+/// arm can leave the switch with a value using `break 'switch VALUE`, or go on to the body of a
+/// later arm using `fallthrough 'label`. A body that runs to the end leaves the switch with its
+/// value.
+///
+/// This expands to a `block!` with nested labeled blocks inside, one for each arm: the arm's label
+/// is given to a block that contains the `match` and the bodies of the arms before it, and its own
+/// body comes right after. So `fallthrough 'label` is just `break 'label`, and it can only jump
+/// forward. As the bodies are outside the `match`, they can't use variables bound by the patterns.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "1000"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// // copies the last `n % 4` bytes, Duff's device style
+/// fn tail(src: &[u8], n: usize) -> Vec<u8> {
+///     let mut out = Vec::new();
+///     let mut i = n - n % 4;
+///     switch!('s: match n % 4 {
+///         3 => 'three: {
+///             out.push(src[i]); i += 1;
+///             fallthrough 'two;
+///         }
+///         2 => 'two: {
+///             out.push(src[i]); i += 1;
+///             fallthrough 'one;
+///         }
+///         1 => 'one: {
+///             out.push(src[i]);
+///         }
+///         _ => 'zero: {}
+///     });
+///     out
+/// }
+/// assert_eq!(tail(b"abcdefg", 7), b"efg");
+/// assert_eq!(tail(b"abcdefg", 5), b"e");
+/// assert!(tail(b"abcdefg", 4).is_empty());
+/// # }
+/// ```
+#[macro_export]
+macro_rules! switch {
+    ($life:tt: match $($rest:tt)+) => {
+        $crate::__named_block_switch!(@rewrite ($life) {} ($($rest)+) -> () ())
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonMatchInSwitch)
+    };
+}
+
+/// Implementation detail of `switch!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_switch {
+    // Rewriter for the tree walker (see walk.rs), which turns `fallthrough 'label` into
+    // `break 'label`. STATE is just the switch's label.
+    (@rewrite $st:tt $paren:tt (fallthrough $label:lifetime $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_switch!(@rewrite $st $paren ($($tail)*) -> ($($out)* break $label) $stack)
+    };
+    (@rewrite $st:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_switch!) $st $paren $input -> $out $stack)
+    };
+    (@skip $st:tt $kind:tt $input:tt ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@done ($life:tt) ($($out:tt)*)) => {
+        $crate::__named_block_switch!(@head $life () $($out)*)
+    };
+
+    // the arms are the last token tree, everything before them is the scrutinee
+    (@head $life:tt ($($e:tt)*) { $($arms:tt)* }) => {
+        $crate::__named_block_switch!(@arms $life ($($e)*) () () () $($arms)*)
+    };
+    (@head $life:tt ($($e:tt)*) $t:tt $($tail:tt)*) => {
+        $crate::__named_block_switch!(@head $life ($($e)* $t) $($tail)*)
+    };
+    (@head $life:tt $e:tt) => {
+        $crate::__named_block_internal!(@error ExpectedArmsInSwitch)
+    };
+
+    // Splits the arms into the arms of the `match`, which break to each label, and a list of
+    // (LABEL (BODY)). The pattern (with its guard, if any) is collected up to the `=>`.
+    (@arms $life:tt $e:tt $dispatch:tt $bodies:tt $pat:tt => $label:lifetime: { $($body:tt)* }, $($tail:tt)*) => {
+        $crate::__named_block_switch!(@arms $life $e $dispatch $bodies $pat => $label: { $($body)* } $($tail)*)
+    };
+    (@arms $life:tt $e:tt ($($dispatch:tt)*) ($($bodies:tt)*) ($($pat:tt)*) => $label:lifetime: { $($body:tt)* }) => {
+        $crate::__named_block_switch!(@nest $life
+            (match $e { $($dispatch)* $($pat)* => break $label, })
+            $($bodies)* ($label ($($body)*)))
+    };
+    (@arms $life:tt $e:tt ($($dispatch:tt)*) ($($bodies:tt)*) ($($pat:tt)*) => $label:lifetime: { $($body:tt)* } $next:tt $($tail:tt)*) => {
+        $crate::__named_block_switch!(@arms $life $e
+            ($($dispatch)* $($pat)* => break $label,)
+            ($($bodies)* ($label ($($body)*)))
+            () $next $($tail)*)
+    };
+    (@arms $life:tt $e:tt $dispatch:tt $bodies:tt $pat:tt => $($tail:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesForArmInSwitch)
+    };
+    (@arms $life:tt $e:tt $dispatch:tt $bodies:tt ($($pat:tt)*) $t:tt $($tail:tt)*) => {
+        $crate::__named_block_switch!(@arms $life $e $dispatch $bodies ($($pat)* $t) $($tail)*)
+    };
+    (@arms $life:tt $e:tt $dispatch:tt $bodies:tt $pat:tt) => {
+        $crate::__named_block_internal!(@error ExpectedArmsInSwitch)
+    };
+
+    // Wraps the code so far in a loop with the next arm's label, followed by that arm's body. The
+    // loops never go around: they're only there to be broken out of, which works on any compiler.
+    // The last body is the value of the whole switch, the others break out of it.
+    (@nest $life:tt ($($inner:tt)*) ($label:tt ($($body:tt)*)) $next:tt $($rest:tt)*) => {
+        $crate::__named_block_switch!(@nest $life
+            ($label: loop { $($inner)* }
+             #[allow(unreachable_code)] { break $life { $($body)* }; })
+            $next $($rest)*)
+    };
+    (@nest $life:tt ($($inner:tt)*) ($label:tt ($($body:tt)*))) => {
+        $crate::block!($life: {
+            $label: loop { $($inner)* }
+            { $($body)* }
+        })
+    };
+}
+
+/// A block where `?` exits the block instead of the enclosing function.
+///
+/// ```text
+/// try_block!('label: { ... })
+/// ```
+///
+/// The block produces a `Result` (or an `Option`): the value of the body, or of a
+/// `break 'label VALUE`, is wrapped in `Ok` (or `Some`), and a `?` that fails exits the block with
+/// the error (or `None`), converted with `From` as usual. An error can be returned explicitly with
+/// `Err(error)?`. The type of the result usually needs to be written down, since the error type
+/// can't be inferred from the `?` alone.
+///
+/// The body is run in a closure, because that's the only way to catch a `?` on stable Rust. So
+/// `return` only exits the block, and a `break` or `continue` to a label outside it doesn't work.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let sum: Result<i32, std::num::ParseIntError> = try_block!('a: {
+///     let a: i32 = "12".parse()?;
+///     let b: i32 = "x".parse()?;
+///     a + b
+/// });
+/// assert!(sum.is_err());
+///
+/// let v = [1, 2, 3];
+/// let last: Option<i32> = try_block!('a: {
+///     if v.is_empty() { break 'a 0; }
+///     *v.last()? * 10
+/// });
+/// assert_eq!(last, Some(30));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! try_block {
+    ($life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_try(|| {
+            $crate::__NamedBlockTryOutput::from_output($crate::block!($life: { $($body)* }))
+        })
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesInTryBlock)
+    };
+}
+
+/// A block that produces a `Result`, in the style of the `catch` blocks from [RFC 243][rfc].
+///
+/// ```text
+/// catch!('label: { ... })
+/// ```
+///
+/// `break 'label Err(error)` exits the block with an error (and `break 'label Ok(value)` with a
+/// success), and the value of the body is wrapped in `Ok`. Unlike `try_block!`, the body is not
+/// moved into a closure, so `return` and breaks to outer labels work as usual (but `?` still
+/// returns from the enclosing function).
+///
+/// [rfc]: https://github.com/rust-lang/rfcs/blob/master/text/0243-trait-based-exception-handling.md
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// fn check(age: i32) -> Result<&'static str, String> {
+///     catch!('a: {
+///         if age < 0 { break 'a Err(format!("{} is negative", age)); }
+///         if age < 18 { "minor" } else { "adult" }
+///     })
+/// }
+/// assert_eq!(check(30), Ok("adult"));
+/// assert_eq!(check(-1), Err(String::from("-1 is negative")));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! catch {
+    ($life:tt: { $($body:tt)* }) => {
+        $crate::block!($life: { Ok({ $($body)* }) })
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesInCatch)
+    };
+}
+
+/// A `block!` for `const` and `static` initializers and `const fn`s.
+///
+/// ```text
+/// const_block!('label: ...)
+/// ```
+///
+/// This takes the same forms as `block!`, except for the ones that can't be evaluated at compile
+/// time, which are rejected with a compile error that says so: a `for` loop (which needs an
+/// iterator), a generator, an `async` block, a closure, and the `timeout` and `every` options of a
+/// loop (which call `Instant::now` and a closure). The other forms expand to `loop`, `match`,
+/// `let` and assignments, which are allowed in const contexts from Rust 1.46 (and labeled blocks
+/// from Rust 1.65, where they are used). That doesn't make the body const, though: it still has
+/// to stick to what const code can do, and so do the values of options like `yield` and the arms
+/// of forms like `catch`.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// const fn parse_digits(s: &[u8]) -> Option<u32> {
+///     const_block!('a: {
+///         let mut i = 0;
+///         let mut n = 0;
+///         while i < s.len() {
+///             if s[i] < b'0' || s[i] > b'9' { break 'a None; }
+///             n = n * 10 + (s[i] - b'0') as u32;
+///             i += 1;
+///         }
+///         Some(n)
+///     })
+/// }
+/// const ANSWER: Option<u32> = parse_digits(b"42");
+/// assert_eq!(ANSWER, Some(42));
+/// assert_eq!(parse_digits(b"4x"), None);
+/// # }
+/// ```
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate named_block;
+/// # use std::time::Duration;
+/// # fn main() {
+/// // TimeoutLoopOptionNotAllowedInConstBlock
+/// const N: u32 = const_block!('a: loop (timeout = Duration::from_secs(1)) { break 'a 1; });
+/// # }
+/// ```
+#[macro_export]
+macro_rules! const_block {
+    ($life:tt: for $($rest:tt)*) => {
+        $crate::__named_block_internal!(@error ForLoopNotAllowedInConstBlock)
+    };
+    ($life:tt: generator $($rest:tt)*) => {
+        $crate::__named_block_internal!(@error GeneratorNotAllowedInConstBlock)
+    };
+    ($life:tt: async $($rest:tt)*) => {
+        $crate::__named_block_internal!(@error AsyncBlockNotAllowedInConstBlock)
+    };
+    ($life:tt: || $($rest:tt)*) => {
+        $crate::__named_block_internal!(@error ClosureNotAllowedInConstBlock)
+    };
+    ($life:tt: | $($rest:tt)*) => {
+        $crate::__named_block_internal!(@error ClosureNotAllowedInConstBlock)
+    };
+    ($life:tt: move $($rest:tt)*) => {
+        $crate::__named_block_internal!(@error ClosureNotAllowedInConstBlock)
+    };
+    ($life:tt: loop ($($opts:tt)*) $($rest:tt)*) => {
+        $crate::__named_block_const_options!(($($opts)*) ($life: loop ($($opts)*) $($rest)*))
+    };
+    ($($args:tt)*) => {
+        $crate::block!($($args)*)
+    };
+}
+
+/// Implementation detail of `const_block!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_const_options {
+    // look through the loop options one token at a time, then go on to block!
+    ((timeout = $($opts:tt)*) $args:tt) => {
+        $crate::__named_block_internal!(@error TimeoutLoopOptionNotAllowedInConstBlock)
+    };
+    ((every = $($opts:tt)*) $args:tt) => {
+        $crate::__named_block_internal!(@error EveryLoopOptionNotAllowedInConstBlock)
+    };
+    (($head:tt $($opts:tt)*) $args:tt) => {
+        $crate::__named_block_const_options!(($($opts)*) $args)
+    };
+    (() ($($args:tt)*)) => {
+        $crate::block!($($args)*)
+    };
+}
+
+/// A loop that checks its condition at the bottom, so that the body runs at least once.
+///
+/// ```text
+/// do_while!('label: { ... } while COND)
+/// ```
+///
+/// As with `block!('label: while COND { ... })`, `break 'label value` leaves the loop with
+/// `Some(value)`, the loop produces `None` once `COND` is false, and `continue 'label` goes on to
+/// the next check of `COND`.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let mut n = 27u64;
+/// let mut steps = 0;
+/// let big = do_while!('a: {
+///     n = if n % 2 == 0 { n / 2 } else { 3 * n + 1 };
+///     steps += 1;
+///     if n > 1000 { break 'a n; }
+/// } while n != 1);
+/// assert_eq!((big, steps), (Some(1186), 36));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! do_while {
+    ($life:tt: { $($body:tt)* } while $cond:expr) => {{
+        let mut first = true;
+        $crate::block!($life: while first || $cond {
+            first = false;
+            $($body)*
+        })
+    }};
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesWhileCondInDoWhile)
+    };
+}
+
+/// A loop that runs its body a given number of times.
+///
+/// ```text
+/// repeat!(N, 'label: { ... })
+/// repeat!(N, 'label: { ... } else { ... })
+/// ```
+///
+/// This is `block!('label: for _ in 0..N { ... })`: `break 'label value` leaves the loop early with
+/// `Some(value)`, and after `N` iterations the loop produces `None`, or the value of the `else` arm
+/// if there is one.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let mut attempts = 0;
+/// let result = repeat!(3, 'a: {
+///     attempts += 1;
+///     if attempts == 2 { break 'a Ok(attempts); }
+/// } else {
+///     Err("gave up")
+/// });
+/// assert_eq!(result, Ok(2));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! repeat {
+    ($n:expr, $life:tt: { $($body:tt)* } $($rest:tt)*) => {
+        $crate::block!($life: for _ in 0..$n { $($body)* } $($rest)*)
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedCountCommaLabelColonBracesInRepeat)
+    };
+}
+
+/// A block that is tried again, up to a given number of attempts.
+///
+/// ```text
+/// retry!('label: attempts = N, { ... })
+/// ```
+///
+/// The body runs until it succeeds, which means getting to the end (or to `break 'label value`),
+/// and then `retry!` produces `Ok(value)`. `continue 'label` gives up on the current attempt and
+/// starts the next one, and if all `N` attempts end that way, the result is `Err(Exhausted)`.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// use named_block::Exhausted;
+///
+/// let mut calls = 0;
+/// let mut flaky = || { calls += 1; if calls < 3 { Err("timed out") } else { Ok(calls) } };
+///
+/// let result = retry!('a: attempts = 5, {
+///     match flaky() {
+///         Ok(n) => n * 10,
+///         Err(_) => continue 'a,
+///     }
+/// });
+/// assert_eq!(result, Ok(30));
+///
+/// let result: Result<(), _> = retry!('a: attempts = 2, { continue 'a; });
+/// assert_eq!(result, Err(Exhausted));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! retry {
+    ($life:tt: attempts = $n:expr, { $($body:tt)* }) => {
+        match $crate::block!($life: for _ in 0..$n {
+            #[allow(unreachable_code)] { break $life { $($body)* }; }
+        }) {
+            Some(value) => Ok(value),
+            None => Err($crate::Exhausted),
+        }
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonAttemptsCommaBracesInRetry)
+    };
+}
+
+/// The error from `retry!` when every attempt gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exhausted;
+
+/// Several labeled loops, nested inside each other, with one body.
+///
+/// ```text
+/// nested_loops!('outer for PAT in EXPR, 'inner for PAT in EXPR, ... { ... })
+/// ```
+///
+/// Each loop is written as a label followed by a `for` or `while` header, outermost first, and the
+/// body goes in the innermost loop. The outermost loop is a `block!` loop, so `break 'outer value`
+/// leaves all of them with `Some(value)`, and the whole thing produces `None` if the outermost loop
+/// runs to completion. The inner loops are plain labeled loops, so `break 'inner` and
+/// `continue 'inner` work as usual (and so does `continue 'outer`).
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let grid = [[1, 2, 3], [4, 5, 6], [7, 8, 9]];
+/// let pos = nested_loops!('row for (i, row) in grid.iter().enumerate(),
+///                         'col for (j, &n) in row.iter().enumerate() {
+///     if n % 2 == 0 && n > 4 { break 'row (i, j); }
+/// });
+/// assert_eq!(pos, Some((1, 2)));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! nested_loops {
+    ($life:tt $kw:ident $($rest:tt)+) => {
+        $crate::__named_block_nested_loops!(@split () ($life $kw) ($($rest)+))
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelForOrWhileInNestedLoops)
+    };
+}
+
+/// Implementation detail of `nested_loops!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_nested_loops {
+    // splits the input into loops, each one (LABEL HEADER...), at each `, LABEL`, until the body
+    (@split ($($done:tt)*) ($($cur:tt)+) (, $life:tt $kw:ident $($rest:tt)*)) => {
+        $crate::__named_block_nested_loops!(@split ($($done)* ($($cur)+)) ($life $kw) ($($rest)*))
+    };
+    (@split ($($done:tt)*) $cur:tt ({ $($body:tt)* })) => {
+        $crate::__named_block_nested_loops!(@reverse () ($($done)* $cur) { $($body)* })
+    };
+    (@split $done:tt ($($cur:tt)+) ($t:tt $($rest:tt)*)) => {
+        $crate::__named_block_nested_loops!(@split $done ($($cur)+ $t) ($($rest)*))
+    };
+    (@split $done:tt $cur:tt ()) => {
+        $crate::__named_block_internal!(@error ExpectedBracesAfterLoopsInNestedLoops)
+    };
+
+    // puts the innermost loop first
+    (@reverse ($($acc:tt)*) ($first:tt $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_nested_loops!(@reverse ($first $($acc)*) ($($rest)*) $body)
+    };
+    (@reverse $loops:tt () $body:tt) => {
+        $crate::__named_block_nested_loops!(@nest $loops $body)
+    };
+
+    // wraps the body in each loop from the inside out, and the outermost one in block!
+    (@nest (($life:tt $($head:tt)*) $next:tt $($rest:tt)*) { $($body:tt)* }) => {
+        $crate::__named_block_nested_loops!(@nest ($next $($rest)*) { $life: $($head)* { $($body)* } })
+    };
+    (@nest (($life:tt $($head:tt)*)) $body:tt) => {
+        $crate::block!($life: $($head)* $body)
+    };
+}
+
+/// The body of `fn main`, with early exit to an exit code (needs the `std` feature, and Rust 1.61).
+///
+/// ```text
+/// main_block!('label: { ... })
+/// ```
+///
+/// `break 'label code` leaves the block with `code`, which is an
+/// [`ExitCode`](https://doc.rust-lang.org/std/process/struct.ExitCode.html) or a `u8` to be
+/// turned into one (all the breaks have to agree on which), and getting to the end of the body
+/// (which should have the type `()`) produces `ExitCode::SUCCESS`. Unlike `std::process::exit`,
+/// this runs destructors on the way out.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// use std::process::ExitCode;
+///
+/// fn run(args: &[&str]) -> ExitCode {
+///     main_block!('main: {
+///         if args.is_empty() { break 'main ExitCode::FAILURE; }
+///         for arg in args {
+///             if arg.parse::<i32>().is_err() { break 'main ExitCode::from(2); }
+///         }
+///     })
+/// }
+///
+/// fn main() {
+///     assert_eq!(run(&[]), ExitCode::FAILURE);
+///     assert_eq!(run(&["1", "x"]), ExitCode::from(2));
+///     assert_eq!(run(&["1", "2"]), ExitCode::SUCCESS);
+/// }
+/// ```
+#[cfg(all(feature = "std", named_block_exit_code))]
+#[macro_export]
+macro_rules! main_block {
+    ($life:tt: { $($body:tt)* }) => {
+        $crate::block!($life: { $($body)* }
+                       on $life (code) => $crate::__NamedBlockExitCode::from(code),
+                       on fallthrough (()) => $crate::__NamedBlockExitCode::SUCCESS)
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesInMainBlock)
+    };
+}
+
+/// An iterator whose `next` runs a block (Rust 1.34 or later).
+///
+/// ```text
+/// iter_block!('label: { ... })
+/// iter_block!('label: move { ... })
+/// ```
+///
+/// Each call to `next` runs the body, which is the body of a closure passed to
+/// [`core::iter::from_fn`](https://doc.rust-lang.org/core/iter/fn.from_fn.html), so any state that
+/// has to last from one item to the next lives in variables outside it. A `yield_item!(value)`
+/// statement leaves the body with `Some(value)`, so that `next` returns the item, while
+/// `break 'label` (with no value) and getting to the end of the body both return `None`, which
+/// ends the iterator. With `move`, the closure takes ownership of the variables it uses, so the
+/// iterator can be returned from a function.
+///
+/// Like the breaks in `block!`, `yield_item!` only works directly in the body, not inside a
+/// closure or an item in it.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let (mut a, mut b) = (0u32, 1u32);
+/// let fib = iter_block!('fib: move {
+///     if a > 50 { break 'fib; }
+///     let next = a;
+///     a = b;
+///     b += next;
+///     yield_item!(next);
+/// });
+/// assert_eq!(fib.collect::<Vec<_>>(), [0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+/// # }
+/// ```
+#[cfg(named_block_from_fn)]
+#[macro_export]
+macro_rules! iter_block {
+    ($life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_iter!(@rewrite ($life (iter ())) {} ($($body)*) -> () ())
+    };
+    ($life:tt: move { $($body:tt)* }) => {
+        $crate::__named_block_iter!(@rewrite ($life (iter (move))) {} ($($body)*) -> () ())
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesInIterBlock)
+    };
+}
+
+/// An async stream whose items are produced by a block (needs the `futures` feature, and Rust
+/// 2018).
+///
+/// ```text
+/// stream_block!('label: async (STATE = INIT) { ... })
+/// ```
+///
+/// This is `iter_block!` for async code: the body is run each time the stream is polled for
+/// another item, and it can `.await`. A `yield_item!(value)` statement produces `value` as the
+/// next item, and `break 'label` (with no value) or getting to the end of the body ends the
+/// stream. Since the body is run in a new `async` block each time, the state that lasts from one
+/// item to the next is kept in the variable `STATE`, which starts out as `INIT` and can be
+/// changed by the body. (Other variables from outside are moved into each of those blocks, so the
+/// body can only use ones that can be copied, such as shared references.)
+///
+/// The stream is made with
+/// [`futures::stream::unfold`](https://docs.rs/futures/0.3/futures/stream/fn.unfold.html), so
+/// it can be used with the combinators of `futures` 0.3.
+///
+/// Examples
+/// ========
+///
+/// ```edition2018
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// use futures::executor::block_on;
+/// use futures::stream::StreamExt;
+///
+/// async fn fetch_page(pages: &[&[i32]], n: usize) -> Vec<i32> {
+///     pages.get(n).map_or(vec![], |page| page.to_vec())
+/// }
+///
+/// async fn all_pages(pages: &[&[i32]]) -> Vec<Vec<i32>> {
+///     stream_block!('pages: async (n = 0) {
+///         let page = fetch_page(pages, n).await;
+///         if page.is_empty() { break 'pages; }
+///         n += 1;
+///         yield_item!(page);
+///     }).collect().await
+/// }
+///
+/// # fn main() {
+/// let pages: [&[i32]; 3] = [&[1, 2], &[3], &[]];
+/// assert_eq!(block_on(all_pages(&pages)), vec![vec![1, 2], vec![3]]);
+/// # }
+/// ```
+#[cfg(feature = "futures")]
+#[macro_export]
+macro_rules! stream_block {
+    ($life:tt: $async:ident ($state:ident = $($init:tt)+) { $($body:tt)* }) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($crate::__named_block_iter!(@rewrite ($life (stream $async $state ($($init)+))) {} ($($body)*) -> () ()))
+            ($crate::__named_block_internal!(@error ExpectedLabelColonAsyncStateBracesInStreamBlock)))
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonAsyncStateBracesInStreamBlock)
+    };
+}
+
+/// Produces an item from the body of an `iter_block!` (see there). Anywhere else, it's an error.
+#[macro_export]
+macro_rules! yield_item {
+    ($($args:tt)*) => {
+        $crate::__named_block_internal!(@error YieldItemOutsideIterBlock)
+    };
+}
+
+/// Implementation detail of `iter_block!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_iter {
+    // Rewriter for the tree walker (see walk.rs), used by iter_block! and stream_block!. STATE is
+    // (LABEL THEN): `yield_item!(value)` becomes `break 'label Some(value)`, and a `break 'label`
+    // without a value becomes `break 'label None`. When the walk is done, THEN says what to do
+    // with the result: (iter (MOVE)) puts it in a closure for iter::from_fn, and
+    // (stream ASYNC STATE (INIT)) in an async block for futures::stream::unfold.
+    (@rewrite ($life:tt $then:tt) $paren:tt (yield_item ! ($($v:tt)*) $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_iter!(@rewrite ($life $then) $paren ($($tail)*) -> ($($out)* break $life Some($($v)*)) $stack)
+    };
+    (@rewrite ($life:tt $then:tt) $paren:tt (yield_item ! [$($v:tt)*] $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_iter!(@rewrite ($life $then) $paren ($($tail)*) -> ($($out)* break $life Some($($v)*)) $stack)
+    };
+    (@rewrite ($life:tt $then:tt) $paren:tt (yield_item ! {$($v:tt)*} $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_iter!(@rewrite ($life $then) $paren ($($tail)*) -> ($($out)* break $life Some($($v)*)) $stack)
+    };
+    (@rewrite $st:tt $paren:tt (break $label:lifetime ; $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_iter!(@end $st $label (;) $paren ($($tail)*) -> $out $stack)
+    };
+    (@rewrite $st:tt $paren:tt (break $label:lifetime , $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_iter!(@end $st $label (,) $paren ($($tail)*) -> $out $stack)
+    };
+    (@rewrite $st:tt $paren:tt (break $label:lifetime) -> $out:tt $stack:tt) => {
+        $crate::__named_block_iter!(@end $st $label () $paren () -> $out $stack)
+    };
+    (@rewrite $st:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_iter!) $st $paren $input -> $out $stack)
+    };
+    (@skip $st:tt $kind:tt $input:tt ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@done ($life:tt (iter ($($move:tt)*))) ($($out:tt)*)) => {
+        $crate::__named_block_from_fn($($move)* || {
+            $crate::__named_block_iter!(@next $life { $($out)* })
+        })
+    };
+    (@done ($life:tt (stream $async:ident $state:ident ($($init:tt)*))) ($($out:tt)*)) => {
+        $crate::__named_block_unfold($($init)*, move |state| $async move {
+            #[allow(unused_mut)]
+            let mut $state = state;
+            let item = $crate::__named_block_iter!(@next $life { $($out)* });
+            match item {
+                Some(item) => Some((item, $state)),
+                None => None,
+            }
+        })
+    };
+
+    // the next item (a body that always yields or breaks makes the fallthrough unreachable)
+    (@next $life:tt { $($out:tt)* }) => {{
+        #[allow(unreachable_code)]
+        let item = $crate::block!($life: { $($out)* }
+                                  on $life (item) => item,
+                                  on fallthrough (()) => None);
+        item
+    }};
+
+    // a break without a value ends the iterator if the label matches
+    (@end ($life:tt $then:tt) $label:tt ($($sep:tt)*) $paren:tt $tail:tt -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life == $label {
+                $crate::__named_block_iter!(@rewrite ($life $then) $paren $tail -> ($($out)* break $label None $($sep)*) $stack)
+            } else {
+                $crate::__named_block_iter!(@rewrite ($life $then) $paren $tail -> ($($out)* break $label $($sep)*) $stack)
+            }
+        }
+    };
+}
+
+/// Leaves a named block from code that the block's scanner can't see.
+///
+/// ```text
+/// exit!('label, value)
+/// exit!('label)
+/// ```
+///
+/// `block!` finds the breaks in its body before any macros in there are expanded, so a `break`
+/// that comes out of another macro isn't transformed. Such a macro can use `exit!` instead, which
+/// expands straight to what a `break 'label value` is transformed into (with the value `()` if
+/// it's left out). That is just the value as written, so where the breaks of a form produce
+/// something else, like the `Some(value)` of a `for` loop, `exit!` has to be given that instead.
+/// It works in bare blocks and loops.
+///
+/// The shorthands for common breaks (`guard!`, `or_break!`, `ok_or_break!`, `bail!`, `break_if!`
+/// and `ensure!`) come in two halves. In the body of a `block!`, they are rewritten into plain
+/// code and a break, which is then transformed like any other, so the value is whatever a break
+/// would give. Anywhere else, such as in the expansion of another macro, they are macros written
+/// with `exit!`, so everything said here applies to them too.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// macro_rules! ensure_positive {
+///     ($label:lifetime, $x:expr) => {
+///         if $x <= 0 { exit!($label, Err($x)); }
+///     };
+/// }
+///
+/// fn halve(x: i32) -> Result<i32, i32> {
+///     block!('a: {
+///         ensure_positive!('a, x);
+///         Ok(x / 2)
+///     })
+/// }
+///
+/// # fn main() {
+/// assert_eq!(halve(10), Ok(5));
+/// assert_eq!(halve(-3), Err(-3));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! exit {
+    ($life:lifetime, $e:expr) => {
+        $crate::__named_block_expand!(@exit $life $e)
+    };
+    ($life:lifetime) => {
+        $crate::__named_block_expand!(@exit $life ())
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelCommaValueInExit)
+    };
+}
+
+/// A named block whose body isn't scanned at all.
+///
+/// ```text
+/// block_explicit!('label: { ... })
+/// ```
+///
+/// The body is emitted as it is, so it costs next to nothing to compile, and there's no
+/// recursion limit to run into however long it gets. In exchange, none of the breaks in it are
+/// transformed: the block is left early with `exit!('label, value)` (or `exit!('label)`), which
+/// works in the body and in any macro it calls. `break!('label, value)` isn't available: `break`
+/// is a keyword, so there can't be a macro by that name, and `break!` only works because `block!`
+/// rewrites it while scanning the body, which is exactly what this skips. On Rust 1.65 and later,
+/// the block is a native labeled block, so a plain `break 'label value` works too, but on older
+/// compilers it's a loop, where `continue 'label` (or a bare `break` or `continue`) goes unnoticed
+/// and does the wrong thing.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let xs = [3, 8, -1, 4];
+/// let sum = block_explicit!('a: {
+///     let mut sum = 0;
+///     for &x in &xs {
+///         if x < 0 { exit!('a, None); }
+///         sum += x;
+///     }
+///     Some(sum)
+/// });
+/// assert_eq!(sum, None);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! block_explicit {
+    ($life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_expand!(@wrap $life () ret () { $($body)* })
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesInBlockExplicit)
+    };
+}
+
+/// Binds a pattern, or leaves a named block (Rust 1.65 or later).
+///
+/// ```text
+/// guard!('label, let PAT = EXPR else VALUE);
+/// guard!('label, let PAT = EXPR else);
+/// ```
+///
+/// This is a `let ... else` statement whose `else` arm is `break 'label VALUE` (or just
+/// `break 'label`): if `EXPR` matches `PAT`, its bindings are available in the rest of the block,
+/// and otherwise the block is left with `VALUE`.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let port = |s: &str| block!('a: {
+///     guard!('a, let Some((host, port)) = s.split_once(':') else Err("no port"));
+///     guard!('a, let Ok(port) = port.parse::<u16>() else Err("bad port"));
+///     Ok((host.len(), port))
+/// });
+/// assert_eq!(port("localhost:8080"), Ok((9, 8080)));
+/// assert_eq!(port("localhost"), Err("no port"));
+/// assert_eq!(port("localhost:http"), Err("bad port"));
+/// # }
+/// ```
+#[cfg(named_block_label_break)]
+#[macro_export]
+macro_rules! guard {
+    ($life:lifetime, let $($args:tt)*) => {
+        $crate::__named_block_guard!($life () $($args)*)
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelCommaLetElseInGuard)
+    };
+}
+
+/// Implementation detail of `guard!`. Not public API.
+#[cfg(named_block_label_break)]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_guard {
+    // collect the pattern and initializer up to the `else`
+    ($life:tt ($($init:tt)*) else) => {
+        let $($init)* else { $crate::exit!($life) };
+    };
+    ($life:tt ($($init:tt)*) else $($value:tt)+) => {
+        let $($init)* else { $crate::exit!($life, $($value)+) };
+    };
+    ($life:tt ($($init:tt)*) $head:tt $($tail:tt)*) => {
+        $crate::__named_block_guard!($life ($($init)* $head) $($tail)*)
+    };
+    ($life:tt $init:tt) => {
+        $crate::__named_block_internal!(@error ExpectedLabelCommaLetElseInGuard)
+    };
+}
+
+/// Unwraps an `Option`, or leaves a named block.
+///
+/// ```text
+/// or_break!(OPTION, 'label, VALUE)
+/// or_break!(OPTION, 'label)
+/// ```
+///
+/// If `OPTION` is `Some(x)`, this evaluates to `x`, and if it's `None`, it does
+/// `break 'label VALUE` (or just `break 'label`, in a block of type `()`).
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let lookup = |path: &[&str]| block!('a: {
+///     let first = or_break!(path.first(), 'a, 0);
+///     let second = or_break!(path.get(1), 'a, first.len());
+///     first.len() + second.len()
+/// });
+/// assert_eq!(lookup(&["ab", "cde"]), 5);
+/// assert_eq!(lookup(&["ab"]), 2);
+/// assert_eq!(lookup(&[]), 0);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! or_break {
+    ($opt:expr, $life:lifetime $(,)*) => {
+        match $opt {
+            Some(value) => value,
+            None => $crate::exit!($life),
+        }
+    };
+    ($opt:expr, $life:lifetime, $default:expr $(,)*) => {
+        match $opt {
+            Some(value) => value,
+            None => $crate::exit!($life, $default),
+        }
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedOptionCommaLabelInOrBreak)
+    };
+}
+
+/// Unwraps a `Result`, or leaves a named block with the error.
+///
+/// ```text
+/// ok_or_break!(RESULT, 'label)
+/// ok_or_break!(RESULT, 'label, MAP)
+/// ```
+///
+/// If `RESULT` is `Ok(x)`, this evaluates to `x`, and if it's `Err(e)`, it does
+/// `break 'label Err(e)`, or with `MAP` (a function or closure), `break 'label Err(MAP(e))`. So
+/// it's `?` for a block, and pairs well with the `catch` arm of `block!`.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let area = |w: &str, h: &str| block!('a: {
+///     let w: u32 = ok_or_break!(w.parse(), 'a, |_| "bad width");
+///     let h: u32 = ok_or_break!(h.parse(), 'a, |_| "bad height");
+///     Ok(w * h)
+/// } catch (msg) {
+///     println!("{}", msg);
+///     0
+/// });
+/// assert_eq!(area("3", "4"), 12);
+/// assert_eq!(area("3", "four"), 0);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! ok_or_break {
+    ($res:expr, $life:lifetime $(,)*) => {
+        match $res {
+            Ok(value) => value,
+            Err(error) => $crate::exit!($life, Err(error)),
+        }
+    };
+    ($res:expr, $life:lifetime, $map:expr $(,)*) => {
+        match $res {
+            Ok(value) => value,
+            Err(error) => $crate::exit!($life, Err(($map)(error))),
+        }
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedResultCommaLabelInOkOrBreak)
+    };
+}
+
+/// Leaves a named block with the default value of its type.
+///
+/// ```text
+/// bail!('label)
+/// ```
+///
+/// This is `break 'label Default::default()`, for the paths that give up and produce nothing (an
+/// empty collection, zero, `false`, ...). `block!` only recognizes it with a label, so a `bail!`
+/// from another crate that takes a message, like the one in `anyhow`, is left alone.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// fn words(s: &str) -> Vec<&str> {
+///     block!('a: {
+///         if s.is_empty() { bail!('a); }
+///         s.split(' ').collect()
+///     })
+/// }
+///
+/// # fn main() {
+/// assert_eq!(words("a b"), ["a", "b"]);
+/// assert!(words("").is_empty());
+/// # }
+/// ```
+#[macro_export]
+macro_rules! bail {
+    ($life:lifetime $(,)*) => {
+        $crate::exit!($life, Default::default())
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelInBail)
+    };
+}
+
+/// Leaves a named block if a condition holds.
+///
+/// ```text
+/// break_if!('label, COND, VALUE)
+/// break_if!('label, COND)
+/// ```
+///
+/// This is `if COND { break 'label VALUE }` (or just `break 'label`) on one line, for early exits
+/// that don't need a block of their own.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let sign = |x: i32| block!('a: {
+///     break_if!('a, x < 0, -1);
+///     break_if!('a, x > 0, 1);
+///     0
+/// });
+/// assert_eq!(sign(-5), -1);
+/// assert_eq!(sign(0), 0);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! break_if {
+    ($life:lifetime, $cond:expr $(,)*) => {
+        if $cond { $crate::exit!($life) }
+    };
+    ($life:lifetime, $cond:expr, $value:expr $(,)*) => {
+        if $cond { $crate::exit!($life, $value) }
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelCommaConditionInBreakIf)
+    };
+}
+
+/// Leaves a named block unless a condition holds.
+///
+/// ```text
+/// ensure!('label, COND, VALUE)
+/// ensure!('label, COND)
+/// ```
+///
+/// This is the opposite of `break_if!`: `if !(COND) { break 'label VALUE }` (or just
+/// `break 'label`), so the code after it can count on `COND`. `block!` only recognizes it with a
+/// label, so an `ensure!` from another crate that takes a condition first, like the one in
+/// `anyhow`, is left alone.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let percent = |x: i32| block!('a: {
+///     ensure!('a, x >= 0, Err("negative"));
+///     ensure!('a, x <= 100, Err("over 100"));
+///     Ok(x as u8)
+/// });
+/// assert_eq!(percent(42), Ok(42));
+/// assert_eq!(percent(-1), Err("negative"));
+/// assert_eq!(percent(101), Err("over 100"));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! ensure {
+    ($life:lifetime, $cond:expr $(,)*) => {
+        if !$cond { $crate::exit!($life) }
+    };
+    ($life:lifetime, $cond:expr, $value:expr $(,)*) => {
+        if !$cond { $crate::exit!($life, $value) }
+    };
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelCommaConditionInEnsure)
+    };
+}
+
+/// Implementation detail of `try_block!`: wraps the value of the body. Not public API.
+#[doc(hidden)]
+pub trait __NamedBlockTryOutput<T> {
+    fn from_output(value: T) -> Self;
+}
+
+impl<T, E> __NamedBlockTryOutput<T> for Result<T, E> {
+    fn from_output(value: T) -> Self {
+        Ok(value)
+    }
+}
+
+impl<T> __NamedBlockTryOutput<T> for Option<T> {
+    fn from_output(value: T) -> Self {
+        Some(value)
+    }
+}
+
+/// Implementation detail of `try_block!`: calls the closure that holds the body. Not public API.
+#[doc(hidden)]
+pub fn __named_block_try<R, F: FnOnce() -> R>(f: F) -> R {
+    f()
+}
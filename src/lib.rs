@@ -1,73 +1,281 @@
-// TODO
-// - eliminate the closure hole by recognizing nested calls and shadowed labels, then maintaining a whitelist?
-// - inline @up rule to reduce recursion depth
-
 #![cfg_attr(not(test), no_std)]
 
 // the tests need more recursion to parse all the code
 #![cfg_attr(test, recursion_limit = "1000")]
 
-// on nightly, we can re-export static_cond!
-#![cfg_attr(feature = "nightly", feature(macro_reexport))]
+// the generator tests need nightly
+#![cfg_attr(all(test, feature = "nightly"),
+            feature(coroutines, coroutine_trait, stmt_expr_attributes))]
 
-#[cfg(all(test,                       // for testing ...
-          not(feature = "nightly")))] // ... on beta/stable ...
-#[macro_use]                          // ... we use the macros ...
-#[no_link]                            // ... but no code ...
-extern crate static_cond;             // ... from static-cond
+#[macro_use]
+mod walk;
+#[macro_use]
+mod flow;
 
-#[cfg(feature = "nightly")]    // on nightly ...
-#[macro_use]                   // ... we use the macros ...
-#[no_link]                     // ... but no code ...
-#[macro_reexport(static_cond)] // ... and re-export static-cond! ...
-extern crate static_cond;      // ... from static-cond
+pub use flow::Exhausted;
+#[doc(hidden)]
+pub use flow::{__named_block_try, __NamedBlockTryOutput};
 
-/// Provides the "early exit from any block" control-flow primitive that was mentioned in [RFC 243][link].
-///
-/// If not using the "nightly" Cargo feature, you must depend on `static-cond` and put `#[macro_use] extern crate static_cond;` at the crate root.
-///
-/// See README.md for more details.
-///
-/// [link]: https://github.com/rust-lang/rfcs/blob/master/text/0243-trait-based-exception-handling.md#early-exit-from-any-block
-///
-/// Examples
-/// ========
+#[cfg(test)]
+extern crate core;
+
+#[cfg(named_block_from_fn)]
+#[doc(hidden)]
+pub use core::iter::from_fn as __named_block_from_fn;
+
+#[cfg(named_block_control_flow)]
+#[doc(hidden)]
+pub use core::ops::ControlFlow as __NamedBlockControlFlow;
+
+#[cfg(all(feature = "std", not(test)))]
+extern crate std;
+
+#[cfg(all(feature = "std", named_block_exit_code))]
+#[doc(hidden)]
+pub use std::process::ExitCode as __NamedBlockExitCode;
+
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub use std::time::Instant as __NamedBlockInstant;
+
+#[cfg(feature = "futures")]
+extern crate futures;
+
+#[cfg(feature = "futures")]
+#[doc(hidden)]
+pub use futures::stream::unfold as __named_block_unfold;
+
+/// Implementation detail of `block!`: how a block with exit handlers was exited. Not public API.
+#[doc(hidden)]
+pub enum __NamedBlockExit<B, F> {
+    Break(B),
+    Fallthrough(F),
+}
+
+/// Implementation detail of `block!`. Not public API.
 ///
-/// ```
-/// # #[macro_use] extern crate named_block;
-/// # #[macro_use] extern crate static_cond;
-/// # fn main() {
-/// let x = block!('a: {
-///     break 'a 0;
-///     1
-/// });
-/// assert_eq!(x, 0);
-/// # }
-/// ```
+/// This is a copy of `static_cond!` from the static-cond crate, so that users don't need to depend
+/// on that crate themselves.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_cond {
+    // private rule to define and call the local macro
+    (@go ($d:tt) $lhs:tt $rhs:tt $arm1:tt $arm2:tt) => {{
+        // The arms are passed to the inner macro as arguments, instead of being written into its
+        // rules, so that their tokens keep their own hygiene and edition (a Rust 2018 `async`
+        // written into a macro defined by this crate would become a Rust 2015 identifier).
+        // `$d` is a `$` token from the caller, since there's no way to escape it here.
+        macro_rules! __named_block_cond_inner {
+            ($lhs $lhs { $d($d then:tt)* } $d els:tt) => { $d($d then)* };
+            ($lhs $rhs $d then:tt { $d($d els:tt)* }) => { $d($d els)* };
+        }
+
+        __named_block_cond_inner!($lhs $rhs $arm1 $arm2)
+    }};
+
+    // we evaluate a conditional by generating a new macro (in an inner scope, so name shadowing is
+    // not a big concern) and calling it
+    (if $lhs:tt == $rhs:tt $then:tt else $els:tt) => {
+        $crate::__named_block_cond!(@go ($) $lhs $rhs $then $els)
+    };
+    (if $lhs:tt != $rhs:tt $then:tt else $els:tt) => {
+        $crate::__named_block_cond!(@go ($) $lhs $rhs $els $then)
+    };
+}
+
+// The expansion strategy is chosen by build.rs according to what the compiler supports. Each
+// variant of __named_block_expand! provides four rules: @break (what a matching
+// `break LIFETIME EXPR` turns into), @exit (what exit! turns into, without the scanner's help),
+// @wrap (the final output) and @labeled_block (what to do with a labeled block inside the body).
+
+/// Implementation detail of `block!`. Not public API.
+#[cfg(named_block_label_break)]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_expand {
+    // labeled blocks can be broken out of with a value
+    (@break $life:tt $ret:ident $e:expr) => {
+        break $life $e
+    };
+
+    // labeled blocks inside the body are left as they are
+    (@labeled_block $native:tt $nested:tt) => {
+        $crate::__named_block_internal! $native
+    };
+
+    // exit! is a plain break, as above
+    (@exit $life:tt $e:expr) => {
+        break $life $e
+    };
+
+    // a bare block with exit handlers is the same as a bare block (only its values differ)
+    (@wrap $life:tt (on $($index:tt)*) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_expand!(@wrap $life () $ret $cb $out)
+    };
+    (@wrap $life:tt () $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                // (the match is only there to carry the attribute, here and below)
+                match () {
+                    #[allow(unused_labels)]
+                    () => $life: {
+                        $out
+                    }
+                }
+            })
+    };
+    (@wrap $life:tt (loop) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                match () {
+                    #[allow(unused_labels)]
+                    () => $life: loop {
+                        $out;
+                    }
+                }
+            })
+    };
+    (@wrap $life:tt (loop $state:ident ($($pat:tt)*) ($($start:tt)*)) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                #[allow(unused_mut)]
+                let mut $state = $($start)*;
+                match () {
+                    #[allow(unused_labels)]
+                    () => $life: loop {
+                        let $($pat)* = $state;
+                        $out;
+                    }
+                }
+            })
+    };
+    (@wrap $life:tt ($kw:ident $next:tt $end:tt $($head:tt)*) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                match () {
+                    #[allow(unused_labels)]
+                    () => $life: {
+                        $next: $kw $($head)* {
+                            $out;
+                        }
+                        $crate::__named_block_internal!(@loop_end $end)
+                    }
+                }
+            })
+    };
+}
+
+/// Implementation detail of `block!`. Not public API.
+#[cfg(not(named_block_label_break))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_expand {
+    // loops can be broken out of with a value, so use a loop that only runs once
+    (@break $life:tt $ret:ident $e:expr) => {
+        break $life $e
+    };
+    (@exit $life:tt $e:expr) => {
+        break $life $e
+    };
+
+    // labeled blocks inside the body become nested calls to block!
+    (@labeled_block $native:tt $nested:tt) => {
+        $crate::__named_block_internal! $nested
+    };
+
+    // a bare block with exit handlers is the same as a bare block (only its values differ)
+    (@wrap $life:tt (on $($index:tt)*) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_expand!(@wrap $life () $ret $cb $out)
+    };
+    (@wrap $life:tt () $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                #[allow(unreachable_code)]
+                let $ret = $life: loop {
+                    // (the extra braces put $out at the end of a block, which is where inner
+                    // attributes in the body are allowed, here and below)
+                    break $life ({ $out });
+                };
+                $ret
+            })
+    };
+    (@wrap $life:tt (loop) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                match () {
+                    #[allow(unused_labels)]
+                    () => $life: loop {
+                        $out;
+                    }
+                }
+            })
+    };
+    (@wrap $life:tt (loop $state:ident ($($pat:tt)*) ($($start:tt)*)) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                #[allow(unused_mut)]
+                let mut $state = $($start)*;
+                match () {
+                    #[allow(unused_labels)]
+                    () => $life: loop {
+                        let $($pat)* = $state;
+                        $out;
+                    }
+                }
+            })
+    };
+    (@wrap $life:tt ($kw:ident $next:tt $end:tt $($head:tt)*) $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_internal!(@emit $cb
+            {
+                #[allow(unused_labels)]
+                let $ret = $life: loop {
+                    $next: $kw $($head)* {
+                        $out;
+                    }
+                    break $life $crate::__named_block_internal!(@loop_end $end);
+                };
+                $ret
+            })
+    };
+}
+
+/// Implementation detail of `block!`. Not public API.
+#[cfg(feature = "std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_now {
+    () => {
+        $crate::__NamedBlockInstant::now()
+    };
+}
+
+/// Implementation detail of `block!`. Not public API.
+#[cfg(not(feature = "std"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_now {
+    // the timeout option needs std::time
+    () => {
+        $crate::__named_block_internal!(@error TimeoutLoopOptionNeedsStdFeature)
+    };
+}
+
+/// Implementation detail of `block!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_error {
+    ($err:ident) => {
+        compile_error!(stringify!($err))
+    };
+}
+
+/// Implementation detail of `block!`. Not public API.
 ///
-/// ```
-/// # #[macro_use] extern crate named_block;
-/// # #[macro_use] extern crate static_cond;
-/// # fn main() {
-/// assert_eq!(
-///     42,
-///     block!('a: {
-///         enum Foo { Bar(i32) }
-///         let closure = #[block(ignore)] {
-///             move |Foo::Bar(x): Foo| -> i32 {
-///                 x + block!('a: {
-///                     break 'a 41;
-///                 })
-///             }
-///         };
-///     
-///         closure(Foo::Bar(1))
-///     })
-/// );
-/// # }
-/// ```
+/// All of the private `@` rules live here, so that a malformed call to `block!` only has to be
+/// checked against the public entry arms.
+#[doc(hidden)]
 #[macro_export]
-macro_rules! block {
+macro_rules! __named_block_internal {
     // =======================================================================================
     // PRIVATE RULES
     // =======================================================================================
@@ -80,11 +288,23 @@ macro_rules! block {
     // (see https://danielkeep.github.io/tlborm/book/blk-ast-coercion.html)
     (@as_expr $e:expr) => { $e };
     
+    // utility: the value carried by a matching break (a `for` or `while` loop can also run to
+    // completion: without an `else` arm, its breaks produce Some(value) and the loop itself
+    // produces None, see @loop_end; a block with exit handlers tells its breaks apart from the
+    // end of the body, see @exit_handlers, and numbered breaks also carry their index, see
+    // @exit_index)
+    (@value (on) $e:expr) => { $crate::__NamedBlockExit::Break($e) };
+    (@value (on index $($n:tt)+) $e:expr) => { $crate::__NamedBlockExit::Break(($e, $($n)+)) };
+    (@value (loop $($state:tt)*) $e:expr) => { $e };
+    (@value ($kw:ident $next:tt () $($head:tt)*) $e:expr) => { Some($e) };
+    (@value $lp:tt $e:expr) => { $e };
+    (@loop_end ()) => { None };
+    (@loop_end $alt:tt) => { $alt };
+
     // utility: deliberately cause a compile error with a CamelCaseMessage
-    (@error $err:ident) => {{
-        struct $err;
-        let _: () = $err;
-    }};
+    (@error $err:ident) => {
+        $crate::__named_block_error!($err)
+    };
 
     // ======================================================
     // OUTPUT STAGE
@@ -93,316 +313,3909 @@ macro_rules! block {
     // been processed and we are ready to write out the
     // final expansion.
     
-    // final output from the top level of the macro
-    (@wrap $life:tt () $ret:ident ($($init:tt)*) $out:expr) => {
-        block!(@as_expr
-            {
-                let $ret $($init)*;
-                $life: loop {
-                    $ret = $out;
-                    break $life;
-                }
-                $ret
-            })
+    // final output from the top level of the macro (see __named_block_expand!)
+    (@wrap $life:tt $lp:tt $ret:ident $cb:tt $out:expr) => {
+        $crate::__named_block_expand!(@wrap $life $lp $ret $cb $out)
     };
-    (@wrap $life:tt (loop) $ret:ident ($($init:tt)*) $out:expr) => {
-        block!(@as_expr
-            {
-                let $ret $($init)*;
-                $life: loop {
-                    $out;
-                }
-                $ret
-            })
+
+    // no callback: the expansion is the result
+    (@emit () $result:tt) => {
+        $crate::__named_block_internal!(@as_expr $result)
+    };
+    // callback: pass the expansion to another macro, along with its extra arguments
+    (@emit (($($mac:tt)*) $extra:tt) $result:tt) => {
+        $($mac)* ($extra $result)
     };
 
     // ======================================================
-    // SCANNER STAGE
+    // ENTRY STAGE
     // ======================================================
-    // This is the meat of the macro. It transfers code from
-    // the input (left of ->) to the output (right of ->)
-    // while transforming break statements or triggering
-    // errors as needed.
-
-    // The next four rules handle the end of the input code -- either the macro
-    // is done, or we need to pop the stack and keep walking. We can tell which
-    // it is by checking the context stack. If it's empty, we can go to output.
-    // Otherwise, we take the current output, surround it by the brace type,
-    // and move up the context stack.
-    
-    // no context: we're done!
-    (@scan {} $life:tt $ret:ident () -> ($($out:tt)*) (() $lp:tt $init:tt)) => {
-        block!(@wrap $life $lp $ret $init { $($out)* })
+    // Entry forms that need some parsing before the scanner
+    // can start.
+
+    // `'a | 'b | 'c: ...`: the aliases are collected, and then renamed to
+    // 'a in the breaks and continues of the rest of the invocation, one at
+    // a time (see __named_block_rename!). Then it's back to block! with just
+    // 'a, after PREFIX (`@option` and the like, or nothing).
+    (@aliases $prefix:tt $life:tt ($($aliases:tt)*) ($alias:tt | $($rest:tt)+)) => {
+        $crate::__named_block_internal!(@aliases $prefix $life ($($aliases)* $alias) ($($rest)+))
     };
-    // pop stack and surround with {}
-    (@scan {} $life:tt $ret:ident () -> ($($out:tt)*) $stack:tt) => {
-        block!(@up $life $ret { $($out)* } $stack)
+    (@aliases $prefix:tt $life:tt ($($aliases:tt)*) ($alias:tt : $($rest:tt)+)) => {
+        $crate::__named_block_internal!(@rewrite (@alias $prefix $life ($($aliases)* $alias)) {} ($($rest)+) -> () ())
     };
-    // pop stack and surround with ()
-    (@scan () $life:tt $ret:ident () -> ($($out:tt)*) $stack:tt) => {
-        block!(@up $life $ret ( $($out)* ) $stack)
+    (@aliases $prefix:tt $life:tt $aliases:tt $rest:tt) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonAfterLabelBarInNamedBlock)
     };
-    // pop stack and surround with []
-    (@scan [] $life:tt $ret:ident () -> ($($out:tt)*) $stack:tt) => {
-        block!(@up $life $ret [ $($out)* ] $stack)
+    (@rewrite (@alias ($($prefix:tt)*) $life:tt ()) {} ($($rest:tt)*) -> () ()) => {
+        $crate::block!($($prefix)* $life: $($rest)*)
+    };
+    (@rewrite (@alias $prefix:tt $life:tt ($alias:tt $($aliases:tt)*)) {} $rest:tt -> () ()) => {
+        $crate::__named_block_walk!(($crate::__named_block_rename!)
+                                    ($alias $life (($crate::__named_block_internal!) (@alias $prefix $life ($($aliases)*)) {} () -> () ()))
+                                    {} $rest -> () ())
     };
-    
-    // The next nine rules are triggered when the tree walker encounters a
-    // break/continue statement.
 
-    // bare "break" and "continue" statements are errors (TODO allow bare break?)
-    (@scan $paren:tt $life:tt $ret:ident (break) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret () -> ($($out)* block!(@error NoBareBreakInNamedBlock);) $stack)
+    // `{ BODY }`: the body goes straight to the scanner, with (@sections BODY)
+    // at the bottom of the stack. If the scanner comes across a `'name:` at
+    // the top level that isn't the label of a loop or block (or a lifetime
+    // bound, as in `fn f<'a: 'b>`), it starts over with @sections, which
+    // splits the body into sections at each such label. That way, plain
+    // blocks don't pay for a second pass over the body.
+    (@body $life:tt $cb:tt $body:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret () $cb ()) {} $body -> () (@sections $body))
+        //                              |         |     |    |  |   |   |  |        |  |
+        //                              |         |     |    |  |   |   |  |        |  ^ stack
+        //                              |         |     |    |  |   |   |  |        ^ output so far
+        //                              |         |     |    |  |   |   |  ^ code to be transformed
+        //                              |         |     |    |  |   |   ^ surrounding bracket type
+        //                              |         |     |    |  |   ^ shadowed labels
+        //                              |         |     |    |  ^ callback
+        //                              |         |     |    ^ loop type
+        //                              |         |     ^ block exit variable name (gensym)
+        //                              |         ^ block label
+        //                              ^ start the tree walker!
+    };
+
+    // The arguments of @sections are the label, the callback, the finished
+    // sections as (NAME (CODE)), the current section's name (`()` before the
+    // first label) and code, and the rest of the input. The sections are
+    // lowered onto a loop with state, where `goto 'name` jumps forward (see
+    // __named_block_state_machine!).
+    (@sections $life:tt $cb:tt $done:tt $cur:tt ($($code:tt)*) ($label:lifetime: $next:tt $($tail:tt)*)) => {
+        $crate::__named_block_internal!(@is_section $next
+            (@sections $life $cb $done $cur ($($code)*) ($label: $next $($tail)*) @split)
+            (@sections $life $cb $done $cur ($($code)* $label: $next) ($($tail)*)))
     };
-    (@scan $paren:tt $life:tt $ret:ident (break; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret ($($tail)*) -> ($($out)* block!(@error NoBareBreakInNamedBlock);) $stack)
+    (@sections $life:tt $cb:tt ($($done:tt)*) $cur:tt $code:tt ($label:lifetime:)) => {
+        $crate::__named_block_internal!(@sections $life $cb ($($done)* ($cur $code)) $label () ())
     };
-    (@scan $paren:tt $life:tt $ret:ident (continue) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret () -> ($($out)* block!(@error NoBareContinueInNamedBlock);) $stack)
+    (@sections $life:tt $cb:tt ($($done:tt)*) $cur:tt $code:tt ($label:lifetime: $($tail:tt)*) @split) => {
+        $crate::__named_block_internal!(@sections $life $cb ($($done)* ($cur $code)) $label () ($($tail)*))
     };
-    (@scan $paren:tt $life:tt $ret:ident (continue; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret ($($tail)*) -> ($($out)* block!(@error NoBareContinueInNamedBlock);) $stack)
+    (@sections $life:tt $cb:tt $done:tt $cur:tt ($($code:tt)*) ($t:tt $($tail:tt)*)) => {
+        $crate::__named_block_internal!(@sections $life $cb $done $cur ($($code)* $t) ($($tail)*))
     };
-    // "break LIFETIME;" (no EXPR)
-    (@scan $paren:tt $life1:tt $ret:ident (break $life2:tt; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life1 $ret ($($tail)*) -> ($($out)* break $life2;) $stack)
+    (@sections $life:tt $cb:tt () () $code:tt ()) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret () $cb ()) {} $code -> () ())
     };
-    // "break LIFETIME EXPR": compare the lifetimes, if they match then transform the statement, otherwise leave it alone
-    (@scan $paren:tt $life1:tt $ret:ident (break $life2:tt $e:expr; $($tail:tt)*) -> ($($out:tt)*) ($stack:tt $lp:tt $init:tt)) => {
-        static_cond! {
-            if $life1 == $life2 {
-                block!(@scan $paren $life1 $ret ($($tail)*) -> ($($out)* { $ret = $e; break $life2; }) ($stack $lp ()))
+    (@sections $life:tt $cb:tt ($($done:tt)*) $cur:tt $code:tt ()) => {
+        $crate::__named_block_state_machine!(@number $life $cb () () (1) $($done)* ($cur $code))
+    };
+
+    // utility: is this token, right after `'name:`, the start of a section?
+    (@is_section loop $yes:tt $no:tt) => { $crate::__named_block_internal! $no };
+    (@is_section while $yes:tt $no:tt) => { $crate::__named_block_internal! $no };
+    (@is_section for $yes:tt $no:tt) => { $crate::__named_block_internal! $no };
+    (@is_section { $($body:tt)* } $yes:tt $no:tt) => { $crate::__named_block_internal! $no };
+    (@is_section $bound:lifetime $yes:tt $no:tt) => { $crate::__named_block_internal! $no };
+    (@is_section $other:tt $yes:tt $no:tt) => { $crate::__named_block_internal! $yes };
+
+    // `for PAT in EXPR { BODY }` and `while COND { BODY }`: the body is the
+    // last token tree, unless the loop ends with `else { ... }`. The
+    // loop keyword, the else arm (or `()` without one) and the header are
+    // kept in LOOP, along with a fresh label for the loop itself (see the
+    // @wrap rules in __named_block_expand!).
+    (@loop_entry $kw:ident $life:tt $cb:tt $head:tt $body:tt $(else $alt:tt)* finally { $($fin:tt)* }) => {
+        $crate::__named_block_internal!(@loop_entry $kw $life (($crate::__named_block_internal!) (@finally $cb ($($fin)*))) $head $body $(else $alt)*)
+    };
+    (@loop_entry $kw:ident $life:tt $cb:tt ($($head:tt)*) { $($body:tt)* } else { $($alt:tt)* }) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret ($kw 'next { $($alt)* } $($head)*) $cb ()) {} ($($body)*) -> () ())
+    };
+    (@loop_entry $kw:ident $life:tt $cb:tt ($($head:tt)*) { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret ($kw 'next () $($head)*) $cb ()) {} ($($body)*) -> () ())
+    };
+    (@loop_entry $kw:ident $life:tt $cb:tt ($($head:tt)*) $t:tt $($tail:tt)*) => {
+        $crate::__named_block_internal!(@loop_entry $kw $life $cb ($($head)* $t) $($tail)*)
+    };
+    (@loop_entry $kw:ident $life:tt $cb:tt $head:tt) => {
+        $crate::__named_block_internal!(@error ExpectedBracesAfterLoopHeaderInNamedBlock)
+    };
+
+    // `{ BODY } on 'a (PAT) => EXPR, on fallthrough (PAT) => EXPR`: the
+    // handlers are collected as ((PAT) (EXPR)), defaulting to passing the
+    // value through. Then LOOP is (on), so that the breaks are wrapped in
+    // Break(...), and the body's value is wrapped in Fallthrough(...), and
+    // the @on_exit callback matches on the result.
+    (@exit_handlers $life:tt $cb:tt $brk:tt $fall:tt $body:tt (on fallthrough ($($pat:tt)*) => $e:expr, $($rest:tt)*)) => {
+        $crate::__named_block_internal!(@exit_handlers $life $cb $brk (($($pat)*) ($e)) $body ($($rest)*))
+    };
+    (@exit_handlers $life:tt $cb:tt $brk:tt $fall:tt $body:tt (on fallthrough ($($pat:tt)*) => $e:expr)) => {
+        $crate::__named_block_internal!(@exit_handlers $life $cb $brk (($($pat)*) ($e)) $body ())
+    };
+    (@exit_handlers $life:tt $cb:tt $brk:tt $fall:tt $body:tt (on $label:lifetime ($($pat:tt)*) => $e:expr, $($rest:tt)*)) => {
+        $crate::__named_block_cond! {
+            if $label == $life {
+                $crate::__named_block_internal!(@exit_handlers $life $cb (($($pat)*) ($e)) $fall $body ($($rest)*))
             } else {
-                block!(@scan $paren $life1 $ret ($($tail)*) -> ($($out)* break $life2 $e;) ($stack $lp ()))
+                $crate::__named_block_internal!(@error UnknownLabelInExitHandler)
             }
         }
     };
-    (@scan $paren:tt $life1:tt $ret:ident (break $life2:tt $e:expr) -> ($($out:tt)*) ($stack:tt $lp:tt $init:tt)) => {
-        static_cond! {
-            if $life1 == $life2 {
-                block!(@scan $paren $life1 $ret () -> ($($out)* { $ret = $e; break $life2 }) ($stack $lp ()))
-                    // TODO make sure this isn't adding too many semicolons
-            } else {
-                block!(@scan $paren $life1 $ret () -> ($($out)* break $life2 $e;) ($stack $lp ()))
-            }
+    (@exit_handlers $life:tt $cb:tt $brk:tt $fall:tt $body:tt (on $label:lifetime ($($pat:tt)*) => $e:expr)) => {
+        $crate::__named_block_internal!(@exit_handlers $life $cb $brk $fall $body (on $label ($($pat)*) => $e,))
+    };
+    (@exit_handlers $life:tt $cb:tt $brk:tt $fall:tt ($($body:tt)*) ()) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret (on) (($crate::__named_block_internal!) (@on_exit $brk $fall $cb)) ()) {}
+                                        ($crate::__NamedBlockExit::Fallthrough({ $($body)* })) -> () ())
+    };
+    (@exit_handlers $life:tt $cb:tt $brk:tt $fall:tt $body:tt $handlers:tt) => {
+        $crate::__named_block_internal!(@error ExpectedOnLabelOrFallthroughInNamedBlock)
+    };
+    ((@on_exit (($($bp:tt)*) ($($be:tt)*)) (($($fp:tt)*) ($($fe:tt)*)) ()) { $($out:tt)* }) => {
+        match { $($out)* } {
+            $crate::__NamedBlockExit::Break($($bp)*) => $($be)*,
+            $crate::__NamedBlockExit::Fallthrough($($fp)*) => $($fe)*,
         }
     };
-    // "continue LIFETIME": compare the lifetimes, if they match then error, otherwise leave it alone
-    // (this only applies to bare blocks)
-    (@scan $paren:tt $life1:tt $ret:ident (continue $life2:tt; $($tail:tt)*) -> ($($out:tt)*) ($stack:tt () $init:tt)) => {
-        static_cond! {
-            if $life1 == $life2 {
-                block!(@scan $paren $life1 $ret ($($tail)*) -> ($($out)* block!(@error NoMatchedContinueInNamedBlock);) ($stack () $init))
-            } else {
-                block!(@scan $paren $life1 $ret ($($tail)*) -> ($($out)* continue $life2;) ($stack () $init))
-            }
+    ((@on_exit $brk:tt $fall:tt (($($mac:tt)*) $extra:tt)) { $($out:tt)* }) => {
+        $($mac)* ($extra { $crate::__named_block_internal!((@on_exit $brk $fall ()) { $($out)* }) })
+    };
+
+    // `'a: { ... }, 'b: { ... }, ...`: the invocation is split at each
+    // `, LABEL:` (the first block has been split off already), and each
+    // part becomes a separate call to block! in a tuple. Commas that aren't
+    // followed by a label, like the ones between exit handlers, stay put.
+    (@siblings ($($done:tt)*) ($($cur:tt)+) (, $life:tt : $($tail:tt)*)) => {
+        $crate::__named_block_internal!(@siblings ($($done)* ($($cur)+)) () ($life : $($tail)*))
+    };
+    (@siblings $done:tt ($($cur:tt)*) ($t:tt $($tail:tt)*)) => {
+        $crate::__named_block_internal!(@siblings $done ($($cur)* $t) ($($tail)*))
+    };
+    (@siblings ($($done:tt)*) ($($cur:tt)+) ()) => {
+        ($($crate::block! $done,)* $crate::block!($($cur)+))
+    };
+    (@siblings $done:tt () ()) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesAfterCommaInNamedBlock)
+    };
+
+    // `'a: |ARGS| BODY`: the parameters are collected up to the closing
+    // `|` (the first one has been taken off already, and `||` comes in
+    // without any), and then the label goes on a block that is the body of
+    // the closure, so a break to it is the same as a return.
+    (@closure_args $life:tt $move:tt ($($args:tt)*) (| $($rest:tt)+)) => {
+        $crate::__named_block_internal!(@closure $life $move ($($args)*) ($($rest)+))
+    };
+    (@closure_args $life:tt $move:tt ($($args:tt)*) ($t:tt $($rest:tt)*)) => {
+        $crate::__named_block_internal!(@closure_args $life $move ($($args)* $t) ($($rest)*))
+    };
+    (@closure_args $life:tt $move:tt $args:tt ()) => {
+        $crate::__named_block_internal!(@error ExpectedClosureBodyAfterBarsInNamedBlock)
+    };
+    (@closure $life:tt ($($move:tt)*) ($($args:tt)*) (-> $ret:ty { $($body:tt)* })) => {
+        $($move)* |$($args)*| -> $ret { $crate::block!($life: { $($body)* }) }
+    };
+    (@closure $life:tt ($($move:tt)*) ($($args:tt)*) ({ $($body:tt)* })) => {
+        $($move)* |$($args)*| $crate::block!($life: { $($body)* })
+    };
+    (@closure $life:tt ($($move:tt)*) ($($args:tt)*) (-> $($rest:tt)*)) => {
+        $crate::__named_block_internal!(@error ExpectedBracesAfterClosureReturnTypeInNamedBlock)
+    };
+    (@closure $life:tt ($($move:tt)*) ($($args:tt)*) ($($body:tt)+)) => {
+        $($move)* |$($args)*| $crate::block!($life: { $($body)+ })
+    };
+
+    // `@exit_index 'a: { BODY }`: like exit handlers, except that LOOP is
+    // (on index), which leaves a @value call with (on index) in the output
+    // for each matching break. When the walk is done, the output is walked
+    // again by __named_block_number!, which numbers those calls in source
+    // order, so that the breaks produce Break((value, n)). Then @on_exit
+    // passes those through and turns the end of the body into (value, 0).
+    (@exit_index $life:tt $cb:tt ($($body:tt)*)) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret (on index)
+                                                  (($crate::__named_block_internal!) (@on_exit ((value) (value)) ((value) ((value, 0))) $cb)) ()) {}
+                                        ($crate::__NamedBlockExit::Fallthrough({ $($body)* })) -> () ())
+    };
+
+    // A catch arm gets the block's value, which is a Result: Ok passes
+    // through unwrapped, and Err runs the handler with the error bound to
+    // the pattern. (CB is the next callback, if any.)
+    ((@catch () ($($pat:tt)*) ($($handler:tt)*)) { $($out:tt)* }) => {
+        match { $($out)* } {
+            Ok(value) => value,
+            Err($($pat)*) => { $($handler)* }
         }
     };
-    (@scan $paren:tt $life1:tt $ret:ident (continue $life2:tt) -> ($($out:tt)*) $stack:tt) => {
-        static_cond! {
-            if $life1 == $life2 {
-                block!(@scan $paren $life1 $ret () -> ($($out)* block!(@error NoMatchedContinueInNamedBlock);) $stack)
+    ((@catch (($($mac:tt)*) $extra:tt) ($($pat:tt)*) ($($handler:tt)*)) { $($out:tt)* }) => {
+        $($mac)* ($extra {
+            match { $($out)* } {
+                Ok(value) => value,
+                Err($($pat)*) => { $($handler)* }
+            }
+        })
+    };
+
+    // A finally arm runs after the block's value has been computed, however
+    // the block was exited. This is the callback for the entry forms that
+    // have one (CB is the original callback, if any).
+    ((@finally () ($($fin:tt)*)) { $($out:tt)* }) => {{
+        let value = { $($out)* };
+        { $($fin)* }
+        value
+    }};
+    ((@finally (($($mac:tt)*) $extra:tt) ($($fin:tt)*)) { $($out:tt)* }) => {
+        $($mac)* ($extra {
+            let value = { $($out)* };
+            { $($fin)* }
+            value
+        })
+    };
+
+    // `loop (PAT = INIT) { BODY }`: the pattern is collected up to the `=`,
+    // then LOOP holds a fresh name for the state variable, the pattern and
+    // the initial value.
+    (@loop_state $life:tt $cb:tt ($($pat:tt)*) (= $start:expr) $body:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret (loop _state ($($pat)*) ($start)) $cb ()) {} $body -> () ())
+    };
+    (@loop_state $life:tt $cb:tt ($($pat:tt)*) ($t:tt $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@loop_state $life $cb ($($pat)* $t) ($($rest)*) $body)
+    };
+    (@loop_state $life:tt $cb:tt $pat:tt () $body:tt) => {
+        $crate::__named_block_internal!(@error ExpectedPatternEqualsValueForLoopStateInNamedBlock)
+    };
+
+    // `loop (OPTION = VALUE, ...) { BODY }`: options look like a loop
+    // state, except that the name is one of the ones below. Each option
+    // adds some setup before the loop and a check at the top of each
+    // iteration, which breaks out with the value of the `else` arm (ALT is
+    // `({ ... })`), or panics if there isn't one (ALT is `()`).
+    (@loop_option max_iter $yes:tt $no:tt) => { $crate::__named_block_internal! $yes };
+    (@loop_option timeout $yes:tt $no:tt) => { $crate::__named_block_internal! $yes };
+    (@loop_option every $yes:tt $no:tt) => { $crate::__named_block_internal! $yes };
+    (@loop_option yield $yes:tt $no:tt) => { $crate::__named_block_internal! $yes };
+    (@loop_option $other:tt $yes:tt $no:tt) => { $crate::__named_block_internal! $no };
+    (@loop_options $life:tt () ($($setup:tt)*) ($($check:tt)*) (max_iter = $n:expr, $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@loop_options $life ()
+            ($($setup)* let limit = $n; let mut iterations = 0;)
+            ($($check)*
+             if iterations == limit {
+                 panic!(concat!("loop ", stringify!($life), " ran for more than ", stringify!($n), " iterations"));
+             }
+             iterations += 1;)
+            ($($rest)*) $body)
+    };
+    (@loop_options $life:tt $alt:tt ($($setup:tt)*) ($($check:tt)*) (max_iter = $n:expr, $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@loop_options $life $alt
+            ($($setup)* let limit = $n; let mut iterations = 0;)
+            ($($check)*
+             if iterations == limit {
+                 #[allow(unreachable_code)] { break $life $alt; }
+             }
+             iterations += 1;)
+            ($($rest)*) $body)
+    };
+    (@loop_options $life:tt () ($($setup:tt)*) ($($check:tt)*) (timeout = $d:expr, $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@loop_options $life ()
+            ($($setup)* let timeout = $d; let started = $crate::__named_block_now!();)
+            ($($check)*
+             if started.elapsed() >= timeout {
+                 panic!(concat!("loop ", stringify!($life), " ran for longer than ", stringify!($d)));
+             })
+            ($($rest)*) $body)
+    };
+    (@loop_options $life:tt $alt:tt ($($setup:tt)*) ($($check:tt)*) (timeout = $d:expr, $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@loop_options $life $alt
+            ($($setup)* let timeout = $d; let started = $crate::__named_block_now!();)
+            ($($check)*
+             if started.elapsed() >= timeout {
+                 #[allow(unreachable_code)] { break $life $alt; }
+             })
+            ($($rest)*) $body)
+    };
+    // (`every` doesn't end the loop, it calls the callback with the number of the iteration that
+    // is about to start, which is counted separately from max_iter's)
+    // (and it can't count in steps of 0)
+    (@loop_options $life:tt $alt:tt $setup:tt $check:tt (every = 0 => $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@error EveryLoopOptionNeedsNonZeroCount)
+    };
+    (@loop_options $life:tt $alt:tt ($($setup:tt)*) ($($check:tt)*) (every = $n:expr => $f:expr, $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@loop_options $life $alt
+            ($($setup)*
+             let every = $n;
+             assert!(every != 0, concat!("loop ", stringify!($life), " can't report every 0 iterations"));
+             let mut progress = 0;)
+            ($($check)*
+             progress += 1;
+             if progress % every == 0 {
+                 ($f)(progress);
+             })
+            ($($rest)*) $body)
+    };
+    // (and `yield` just evaluates its expression, such as `yield_now().await`)
+    (@loop_options $life:tt $alt:tt $setup:tt ($($check:tt)*) (yield = $e:expr, $($rest:tt)*) $body:tt) => {
+        $crate::__named_block_internal!(@loop_options $life $alt $setup ($($check)* $e;) ($($rest)*) $body)
+    };
+    (@loop_options $life:tt $alt:tt ($($setup:tt)*) ($($check:tt)*) ($(,)*) ($($body:tt)*)) => {{
+        $($setup)*
+        $crate::block!($life: loop { $($check)* $($body)* })
+    }};
+    (@loop_options $life:tt $alt:tt $setup:tt $check:tt $opts:tt $body:tt) => {
+        $crate::__named_block_internal!(@error ExpectedOptionEqualsValueForLoopInNamedBlock)
+    };
+
+    // ======================================================
+    // SCANNER STAGE
+    // ======================================================
+    // This is the meat of the macro. The tree walker (see
+    // walk.rs) transfers code from the input (left of ->) to
+    // the output (right of ->), and these rules transform
+    // break statements or trigger errors as needed.
+    //
+    // The walker state is (LABEL RET LOOP CALLBACK SHADOWED).
+    //
+    // SHADOWED is a list of labels declared inside the block, innermost first.
+    // When the walker is inside a loop labeled with the block's own label, a
+    // matching break or continue refers to that loop and must be left alone.
+
+    // The walk is done: go to output (after numbering the breaks, see @exit_index).
+    (@done ($life:tt $ret:ident (on index) $cb:tt $shadow:tt) ($($out:tt)*)) => {
+        $crate::__named_block_number!(@rewrite ((1usize) ($life $ret $cb)) {} ($($out)*) -> () ())
+    };
+    (@done ($life:tt $ret:ident $lp:tt $cb:tt $shadow:tt) ($($out:tt)*)) => {
+        $crate::__named_block_internal!(@wrap $life $lp $ret $cb { $($out)* })
+    };
+
+    // utility: check whether a label is in the shadowed list, then continue
+    // with one of two sets of arguments to this macro
+    (@shadowed $life:tt () $yes:tt $no:tt) => {
+        $crate::__named_block_internal! $no
+    };
+    (@shadowed $life:tt ($head:tt $($rest:tt)*) $yes:tt $no:tt) => {
+        $crate::__named_block_cond! {
+            if $life == $head {
+                $crate::__named_block_internal! $yes
             } else {
-                block!(@scan $paren $life1 $ret () -> ($($out)* continue $life2;) $stack)
+                $crate::__named_block_internal!(@shadowed $life ($($rest)*) $yes $no)
             }
         }
     };
 
-    // tree walker ignores #[block(ignore)] tts, closures, and items
-    
-    (@scan_item $paren:tt $life:tt $ret:ident ($ignore:item $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret ($($tail)*) -> ($($out)* $ignore) $stack)
+    // utility: check whether the walker is in the arguments of a macro call
+    // (other than block!), which push a `!` onto the shadowed list, then
+    // continue with one of two sets of arguments to this macro
+    (@in_macro () $yes:tt $no:tt) => {
+        $crate::__named_block_internal! $no
     };
-    
-    // #[block(ignore)] attribute is ignored
-    (@scan $paren:tt $life:tt $ret:ident (#[block(ignore)] $ignore:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret ($($tail)*) -> ($($out)* $ignore) $stack)
+    (@in_macro (! $($rest:tt)*) $yes:tt $no:tt) => {
+        $crate::__named_block_internal! $yes
     };
-    // other attributes pass through
-    (@scan $paren:tt $life:tt $ret:ident (#[$attr:meta] $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret ($($tail)*) -> ($($out)* #[$attr]) $stack)
+    (@in_macro ($head:tt $($rest:tt)*) $yes:tt $no:tt) => {
+        $crate::__named_block_internal!(@in_macro ($($rest)*) $yes $no)
     };
-    // ignore items: use, extern, static, const, unsafe trait/impl/fn, fn, mod, type, enum, trait, impl, struct
-    (@scan $paren:tt $life:tt $ret:ident (pub $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (pub $($tail)*) -> $out $stack)
+
+    // A labeled loop shadows its label until the end of its body. The
+    // @unshadow marker is left in the input, so that it's found when the
+    // walker pops out of the body.
+    //
+    // For `while` and `for`, the header is copied over untouched until the
+    // first brace group, which is the body (struct literals aren't allowed
+    // there without parentheses, so this is usually right).
+    //
+    // A native labeled block (`'x: { ... }`, Rust 1.65) is treated the same
+    // way, so breaks that target it are left alone. On older compilers, it
+    // becomes a nested call to block! instead (see @nested_label), so the
+    // breaks that target the inner label are transformed by the nested
+    // call. (Not on newer ones, because the nested call counts towards the
+    // recursion limit on top of this one.) In the arguments of another
+    // macro, which may have its own idea of what a label means (like
+    // state_machine!), it's always left alone.
+    //
+    // Reusing the block's own label is usually a mistake, so it's an error
+    // unless the loop is marked with #[block(shadow)] (see @check_label).
+    (@rewrite $state:tt $paren:tt ($label:lifetime: loop $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@loop_label () $label loop $state $paren ($($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (use $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (use $($tail)*) -> $out $stack)
+    (@rewrite $state:tt $paren:tt ($label:lifetime: while $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@loop_label () $label while $state $paren ($($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (extern $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (extern $($tail)*) -> $out $stack)
+    (@rewrite $state:tt $paren:tt ($label:lifetime: for $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@loop_label () $label for $state $paren ($($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (mod $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (mod $($tail)*) -> $out $stack)
+    (@rewrite $state:tt $paren:tt (#[block(shadow)] $label:lifetime: loop $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@loop_label (shadow) $label loop $state $paren ($($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (static $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (static $($tail)*) -> $out $stack)
+    (@rewrite $state:tt $paren:tt (#[block(shadow)] $label:lifetime: while $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@loop_label (shadow) $label while $state $paren ($($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (const $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (const $($tail)*) -> $out $stack)
+    (@rewrite $state:tt $paren:tt (#[block(shadow)] $label:lifetime: for $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@loop_label (shadow) $label for $state $paren ($($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (trait $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (trait $($tail)*) -> $out $stack)
+    (@rewrite $state:tt $paren:tt ($label:lifetime: { $($body:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@block_label () $label $state $paren ({ $($body)* } $($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (unsafe trait $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (unsafe trait $($tail)*) -> $out $stack)
+    (@rewrite $state:tt $paren:tt (#[block(shadow)] $label:lifetime: { $($body:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@block_label (shadow) $label $state $paren ({ $($body)* } $($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (impl $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (impl $($tail)*) -> $out $stack)
+    // At the top level of a bare block (see @body), any other `'name:` starts
+    // a section, so the scan is abandoned and the body is split first.
+    (@rewrite $state:tt {} ($label:lifetime: $bound:lifetime $($tail:tt)*) -> $out:tt (@sections $body:tt)) => {
+        $crate::__named_block_walk!(($crate::__named_block_internal!) $state {} ($label: $bound $($tail)*) -> $out (@sections $body))
     };
-    (@scan $paren:tt $life:tt $ret:ident (unsafe impl $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (unsafe impl $($tail)*) -> $out $stack)
+    (@rewrite ($life:tt $ret:ident $lp:tt $cb:tt $shadow:tt) {} ($label:lifetime: $($tail:tt)*) -> $out:tt (@sections $body:tt)) => {
+        $crate::__named_block_internal!(@sections $life $cb () () () $body)
     };
-    (@scan $paren:tt $life:tt $ret:ident (fn $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (fn $($tail)*) -> $out $stack)
+    // end of a bare block without sections
+    (@rewrite $state:tt {} () -> $out:tt (@sections $body:tt)) => {
+        $crate::__named_block_walk!(($crate::__named_block_internal!) $state {} () -> $out ())
     };
-    (@scan $paren:tt $life:tt $ret:ident (unsafe fn $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (unsafe fn $($tail)*) -> $out $stack)
+    (@block_label $allow:tt $label:tt ($life:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt ({ $($body:tt)* } $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@in_macro $shadow
+            (@check_label $allow $life $label
+                (@loop_header $label ($life $ret $lp $cb $shadow) $paren ({ $($body)* } $($tail)*) -> ($($out)* $label:) $stack))
+            (@labeled_block
+                (@check_label $allow $life $label
+                    (@loop_header $label ($life $ret $lp $cb $shadow) $paren ({ $($body)* } $($tail)*) -> ($($out)* $label:) $stack))
+                (@nested_label $allow $label ($life $ret $lp $cb $shadow) $paren ({ $label: { $($body)* } } $($tail)*) -> ($($out)* $crate::) $stack)))
     };
-    (@scan $paren:tt $life:tt $ret:ident (type $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (type $($tail)*) -> $out $stack)
+    (@labeled_block $native:tt $nested:tt) => {
+        $crate::__named_block_expand!(@labeled_block $native $nested)
     };
-    (@scan $paren:tt $life:tt $ret:ident (enum $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (enum $($tail)*) -> $out $stack)
+    (@loop_label $allow:tt $label:tt $kw:tt ($life:tt $($state:tt)*) $paren:tt $tail:tt -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@check_label $allow $life $label
+            (@loop_header $label ($life $($state)*) $paren $tail -> ($($out)* $label: $kw) $stack))
     };
-    (@scan $paren:tt $life:tt $ret:ident (struct $($tail:tt)*) -> $out:tt $stack:tt) => {
-        block!(@scan_item $paren $life $ret (struct $($tail)*) -> $out $stack)
+    (@loop_header $label:tt ($life:tt $ret:ident $lp:tt $cb:tt ($($shadow:tt)*)) $paren:tt ({ $($body:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life $ret $lp $cb ($label $($shadow)*)) {} ($($body)*) -> ()
+                                        ($paren (@unshadow $($tail)*) -> $out $stack))
     };
-    
-    // tree walker descends into token trees
-    (@scan $paren:tt $life:tt $ret:ident ({ $($inner:tt)* } $($tail:tt)*) -> $out:tt ($stack:tt $lp:tt $init:tt)) => {
-        block!(@scan {} $life $ret ($($inner)*) -> ()
-               (($paren ($($tail)*) -> $out $stack) $lp $init))
+    (@loop_header $label:tt $state:tt $paren:tt ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@loop_header $label $state $paren ($($tail)*) -> ($($out)* $head) $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident (( $($inner:tt)* ) $($tail:tt)*) -> $out:tt ($stack:tt $lp:tt $init:tt)) => {
-        block!(@scan () $life $ret ($($inner)*) -> ()
-               (($paren ($($tail)*) -> $out $stack) $lp $init))
+    // A nested call to block! shadows its own label in the same way (and
+    // is checked in the same way). The body is still walked (after the
+    // label, which was just checked), so that breaks to the outer label are
+    // transformed.
+    //
+    // This is how breaks cross invocations: macros expand from the outside
+    // in, so the outer call always sees `break 'outer EXPR` first and turns
+    // it into __named_block_expand!(@break 'outer RET EXPR). RET is the outer
+    // call's hygienic return slot, so it can't be confused with the inner
+    // one, and the inner call walks right past the rewritten form because
+    // it doesn't start with `break`.
+    (@rewrite $state:tt $paren:tt (block ! ($label:lifetime : $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@nested_label () $label $state $paren (($label : $($args)*) $($tail)*) -> $out $stack)
     };
-    (@scan $paren:tt $life:tt $ret:ident ([ $($inner:tt)* ] $($tail:tt)*) -> $out:tt ($stack:tt $lp:tt $init:tt)) => {
-        block!(@scan [] $life $ret ($($inner)*) -> ()
-               (($paren ($($tail)*) -> $out $stack) $lp $init))
+    (@rewrite $state:tt $paren:tt (block ! [$label:lifetime : $($args:tt)*] $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@nested_label () $label $state $paren ([$label : $($args)*] $($tail)*) -> $out $stack)
     };
-
-    // fall-through case for tree walker: transfer over a token
-    (@scan $paren:tt $life:tt $ret:ident ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
-        block!(@scan $paren $life $ret ($($tail)*) -> ($($out)* $head) $stack)
+    (@rewrite $state:tt $paren:tt (block ! {$label:lifetime : $($args:tt)*} $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@nested_label () $label $state $paren ({$label : $($args)*} $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (#[block(shadow)] block ! ($label:lifetime : $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@nested_label (shadow) $label $state $paren (($label : $($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (#[block(shadow)] block ! [$label:lifetime : $($args:tt)*] $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@nested_label (shadow) $label $state $paren ([$label : $($args)*] $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (#[block(shadow)] block ! {$label:lifetime : $($args:tt)*} $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@nested_label (shadow) $label $state $paren ({$label : $($args)*} $($tail)*) -> $out $stack)
+    };
+    (@nested_label $allow:tt $label:tt ($life:tt $ret:ident $lp:tt $cb:tt ($($shadow:tt)*)) $paren:tt ({ $decl:tt : $($args:tt)* } $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@check_label $allow $life $label
+            (@rewrite ($life $ret $lp $cb ($label $($shadow)*)) {} ($($args)*) -> ($decl :)
+             ($paren (@unshadow $($tail)*) -> ($($out)* block!) $stack)))
+    };
+    (@nested_label $allow:tt $label:tt ($life:tt $ret:ident $lp:tt $cb:tt ($($shadow:tt)*)) $paren:tt (( $decl:tt : $($args:tt)* ) $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@check_label $allow $life $label
+            (@rewrite ($life $ret $lp $cb ($label $($shadow)*)) () ($($args)*) -> ($decl :)
+             ($paren (@unshadow $($tail)*) -> ($($out)* block!) $stack)))
+    };
+    (@nested_label $allow:tt $label:tt ($life:tt $ret:ident $lp:tt $cb:tt ($($shadow:tt)*)) $paren:tt ([ $decl:tt : $($args:tt)* ] $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@check_label $allow $life $label
+            (@rewrite ($life $ret $lp $cb ($label $($shadow)*)) [] ($($args)*) -> ($decl :)
+             ($paren (@unshadow $($tail)*) -> ($($out)* block!) $stack)))
+    };
+    // `break!('a, VALUE)` is the same as `break 'a VALUE`, except that the
+    // value is delimited, so it can't run into what comes after it. It's put
+    // back into the input as a break with a parenthesized value (or no
+    // value), and the value is walked like the block-started ones below.
+    (@rewrite $state:tt $paren:tt (break ! ($label:lifetime $(,)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (break $label $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break ! ($label:lifetime, $($value:tt)+) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_macro $label () ($($value)+) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error ExpectedLabelCommaValueInBreakMacro)) $stack)
+    };
+    // drop a trailing comma from the value
+    (@rewrite $state:tt $paren:tt (@break_macro $label:tt ($($value:tt)*) (,) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $label ($($value)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_macro $label:tt ($($value:tt)*) () $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $label ($($value)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_macro $label:tt ($($value:tt)*) ($head:tt $($rest:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_macro $label ($($value)* $head) ($($rest)*) $($tail)*) -> $out $stack)
+    };
+    // `bail!('a)` breaks with `Default::default()`
+    (@rewrite $state:tt $paren:tt (bail ! ($label:lifetime $(,)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (break ! ($label, Default::default()) $($tail)*) -> $out $stack)
     };
 
-    // reformats arguments when popping a context off the tree walker stack
-    // TODO this could be folded into the @scan rules that call it, to reduce recursion depth
-    (@up $life:tt $ret:ident $thing:tt (($paren:tt $tail:tt -> ($($out:tt)*) $stack:tt) $lp:tt $init:tt)) => {
-        block!(@scan $paren $life $ret $tail -> ($($out)* $thing) ($stack $lp $init))
+    // `break_if!('a, COND, VALUE)` is put back into the input as
+    // `if COND { break!('a, VALUE) }`, and `ensure!('a, COND, VALUE)` as
+    // `if !(COND) { break!('a, VALUE) }`, once the end of COND has been
+    // found (a top-level comma can't be part of an expression).
+    (@rewrite $state:tt $paren:tt (break_if ! ($label:lifetime, $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_if (if) $label () ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (ensure ! ($label:lifetime, $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_if (unless) $label () ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_if $mode:tt $label:tt ($($cond:tt)+) (, $($value:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_when $mode ($($cond)+) { break ! ($label, $($value)*) } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_if $mode:tt $label:tt ($($cond:tt)+) () $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_when $mode ($($cond)+) { break ! ($label) } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_if $mode:tt $label:tt ($($cond:tt)*) ($head:tt $($rest:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_if $mode $label ($($cond)* $head) ($($rest)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_if (if) $label:tt () () $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error ExpectedLabelCommaConditionInBreakIf)) $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_if (unless) $label:tt () () $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error ExpectedLabelCommaConditionInEnsure)) $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_when (if) ($($cond:tt)+) $body:tt $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (if $($cond)+ $body $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@break_when (unless) ($($cond:tt)+) $body:tt $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (if !($($cond)+) $body $($tail)*) -> $out $stack)
     };
 
-    // entry point for bare block
-    ($life:tt: { $($body:tt)* }) => {
-        block!(@scan {} $life _ret ($($body)*) -> () (() () ()))
-        //      |    |  |     |    |              |  ||  |  |
-        //      |    |  |     |    |              |  ||  |  ^ initialization
-        //      |    |  |     |    |              |  ||  ^ loop type
-        //      |    |  |     |    |              |  |^ tree walker stack
-        //      |    |  |     |    |              |  ^ passed-through context
-        //      |    |  |     |    |              ^ transformed code
-        //      |    |  |     |    ^ code to be transformed
-        //      |    |  |     ^ block exit variable name (gensym)
-        //      |    |  ^ block label
-        //      |    ^ surrounding bracket type
-        //      ^ start the tree walker!
+    // `guard!('a, let PAT = EXPR else VALUE)` is put back into the input as
+    // `let PAT = EXPR else { break!('a, VALUE) }` (the statement's `;` is
+    // the caller's), once the `else` has been found. A pattern can't contain
+    // `else`, and neither can the end of a let-else initializer, so the
+    // first one is it.
+    (@rewrite $state:tt $paren:tt (guard ! ($label:lifetime, let $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@guard $label () ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@guard $label:tt ($($init:tt)*) (else $($value:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (let $($init)* else { break ! ($label, $($value)*) } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@guard $label:tt ($($init:tt)*) ($head:tt $($rest:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@guard $label ($($init)* $head) ($($rest)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@guard $label:tt $init:tt () $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error ExpectedLabelCommaLetElseInGuard)) $stack)
     };
 
-    // entry point for loop
-    ($life:tt: loop { $($body:tt)* }) => {
-        block!(@scan {} $life _ret ($($body)*) -> () (() (loop) (= ())))
+    // `or_break!(OPT, 'a, DEFAULT)` is put back into the input as
+    // `match OPT { Some(value) => value, None => break!('a, DEFAULT) }`,
+    // once the `, 'a` has been found, and `ok_or_break!(RES, 'a, MAP)` as
+    // `match RES { Ok(value) => value, Err(error) => break!('a, Err(MAP(error))) }`.
+    (@rewrite $state:tt $paren:tt (or_break ! ($($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@or_break (option) () ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (ok_or_break ! ($($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@or_break (result) () ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@or_break (option) ($($opt:tt)+) (, $label:lifetime $(,)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (match $($opt)+ { Some(value) => value, None => break ! ($label) } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@or_break (option) ($($opt:tt)+) (, $label:lifetime, $($default:tt)+) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (match $($opt)+ { Some(value) => value, None => break ! ($label, $($default)+) } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@or_break (result) ($($res:tt)+) (, $label:lifetime $(,)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (match $($res)+ { Ok(value) => value, Err(error) => break ! ($label, Err(error)) } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@or_break (result) ($($res:tt)+) (, $label:lifetime, $($map:tt)+) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (match $($res)+ { Ok(value) => value, Err(error) => break ! ($label, Err(($($map)+)(error))) } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@or_break $kind:tt ($($opt:tt)*) ($head:tt $($rest:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@or_break $kind ($($opt)* $head) ($($rest)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@or_break (option) $opt:tt () $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error ExpectedOptionCommaLabelInOrBreak)) $stack)
+    };
+    (@rewrite $state:tt $paren:tt (@or_break (result) $res:tt () $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error ExpectedResultCommaLabelInOkOrBreak)) $stack)
     };
-}
+
+    // The arguments of any other macro call are walked too (that's up to
+    // the macro, but they usually contain code), with a `!` pushed onto the
+    // shadowed list, see @in_macro. Inline assembly is left to the walker,
+    // which doesn't look inside.
+    (@rewrite $state:tt $paren:tt (asm ! $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_internal!) $state $paren (asm ! $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (global_asm ! $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_internal!) $state $paren (global_asm ! $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($life:tt $ret:ident $lp:tt $cb:tt ($($shadow:tt)*)) $paren:tt ($name:ident ! { $($args:tt)* } $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life $ret $lp $cb (! $($shadow)*)) {} ($($args)*) -> ()
+                                        ($paren (@unshadow $($tail)*) -> ($($out)* $name !) $stack))
+    };
+    (@rewrite ($life:tt $ret:ident $lp:tt $cb:tt ($($shadow:tt)*)) $paren:tt ($name:ident ! ( $($args:tt)* ) $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life $ret $lp $cb (! $($shadow)*)) () ($($args)*) -> ()
+                                        ($paren (@unshadow $($tail)*) -> ($($out)* $name !) $stack))
+    };
+    (@rewrite ($life:tt $ret:ident $lp:tt $cb:tt ($($shadow:tt)*)) $paren:tt ($name:ident ! [ $($args:tt)* ] $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life $ret $lp $cb (! $($shadow)*)) [] ($($args)*) -> ()
+                                        ($paren (@unshadow $($tail)*) -> ($($out)* $name !) $stack))
+    };
+    // utility: continue with the given arguments to this macro, unless a
+    // label that's being declared is the same as the block's own label (and
+    // that wasn't allowed with #[block(shadow)])
+    (@check_label (shadow) $life:tt $label:tt $next:tt) => {
+        $crate::__named_block_internal! $next
+    };
+    (@check_label () $life:tt $label:tt $next:tt) => {
+        $crate::__named_block_cond! {
+            if $life == $label {
+                $crate::__named_block_internal!(@error DuplicateLabelInNamedBlock)
+            } else {
+                $crate::__named_block_internal! $next
+            }
+        }
+    };
+    (@rewrite ($life:tt $ret:ident $lp:tt $cb:tt ($head:tt $($shadow:tt)*)) $paren:tt (@unshadow $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life $ret $lp $cb ($($shadow)*)) $paren ($($tail)*) -> $out $stack)
+    };
+
+    // The next rules are triggered when the tree walker encounters a
+    // break/continue statement.
+
+    // bare "break" and "continue" statements are errors (TODO allow bare break?)
+    (@rewrite $state:tt $paren:tt (break) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren () -> ($($out)* $crate::__named_block_internal!(@error NoBareBreakInNamedBlock)) $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error NoBareBreakInNamedBlock);) $stack)
+    };
+    (@rewrite $state:tt $paren:tt (continue) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren () -> ($($out)* $crate::__named_block_internal!(@error NoBareContinueInNamedBlock)) $stack)
+    };
+    (@rewrite $state:tt $paren:tt (continue; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error NoBareContinueInNamedBlock);) $stack)
+    };
+    // "break LIFETIME" (no EXPR): if the lifetimes match, this is the same as breaking with ()
+    //
+    // (these rules, and the ones for "break LIFETIME EXPR", come in three
+    // flavors: ending with `;`, ending with `,` as in a match arm, and at the
+    // end of the token tree. The terminator is copied over as-is, so that a
+    // break works anywhere an expression can go, e.g. `f(break 'a 1)`. The
+    // transformed break is a bare macro call, not a block, so that it keeps
+    // the type of a `break` where nothing else pins it down, as in the
+    // arguments of `assert!`, `write!` and other std formatting macros.)
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt (break $life2:tt; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2;) $stack)
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* $crate::__named_block_expand!(@break $life2 $ret $crate::__named_block_internal!(@value $lp ()));) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2;) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt (break $life2:tt, $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2,) $stack)
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* $crate::__named_block_expand!(@break $life2 $ret $crate::__named_block_internal!(@value $lp ())),) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2,) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt (break $life2:tt) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren () -> ($($out)* break $life2) $stack)
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren () -> ($($out)* $crate::__named_block_expand!(@break $life2 $ret $crate::__named_block_internal!(@value $lp ()))) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren () -> ($($out)* break $life2) $stack)
+            }
+        }
+    };
+    // "break LIFETIME VALUE" where VALUE starts with a block (`match`, `if`,
+    // `loop`, `unsafe` or just braces) or is a struct literal: there may be more breaks inside it,
+    // so it can't be swallowed as an $e:expr like other values. Instead the
+    // value is collected up to the next `;` or `,` and walked in a frame
+    // of its own, with (@break LIFETIME) as the bracket type. When that frame
+    // runs out of input, it is closed by wrapping the walked value in a
+    // transformed break.
+    (@rewrite $state:tt $paren:tt (break $life2:tt match $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $life2 match $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break $life2:tt if $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $life2 if $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break $life2:tt loop $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $life2 loop $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break $life2:tt unsafe $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $life2 unsafe $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break $life2:tt { $($body:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $life2 { $($body)* } $($tail)*) -> $out $stack)
+    };
+    (@rewrite $state:tt $paren:tt (break $life2:tt $($path:ident)::+ { $($fields:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_block $life2 $($path)::+ { $($fields)* } $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt (@break_block $life2:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2) $stack)
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren (@break_value $life2 () $($tail)*) -> ($($out)*) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2) $stack)
+            }
+        }
+    };
+    // collect the value, then start walking it
+    (@rewrite $state:tt $paren:tt (@break_value $life2:tt ($($value:tt)*) ; $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state (@break $life2) ($($value)*) -> () ($paren (; $($tail)*) -> $out $stack))
+    };
+    (@rewrite $state:tt $paren:tt (@break_value $life2:tt ($($value:tt)*) , $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state (@break $life2) ($($value)*) -> () ($paren (, $($tail)*) -> $out $stack))
+    };
+    (@rewrite $state:tt $paren:tt (@break_value $life2:tt ($($value:tt)*)) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state (@break $life2) ($($value)*) -> () ($paren () -> $out $stack))
+    };
+    (@rewrite $state:tt $paren:tt (@break_value $life2:tt ($($value:tt)*) $head:tt $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite $state $paren (@break_value $life2 ($($value)* $head) $($tail)*) -> $out $stack)
+    };
+    // the value has been walked
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) (@break $life2:tt) () -> ($($value:tt)*) ($paren:tt $tail:tt -> ($($out:tt)*) $stack:tt)) => {
+        $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren $tail -> ($($out)* $crate::__named_block_expand!(@break $life2 $ret $crate::__named_block_internal!(@value $lp $($value)*))) $stack)
+    };
+
+    // "break LIFETIME EXPR": compare the lifetimes, if they match (and the label isn't shadowed) then transform the statement, otherwise leave it alone
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt (break $life2:tt $e:expr; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2 $e;) $stack)
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* $crate::__named_block_expand!(@break $life2 $ret $crate::__named_block_internal!(@value $lp $e));) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2 $e;) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt (break $life2:tt $e:expr, $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2 $e,) $stack)
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* $crate::__named_block_expand!(@break $life2 $ret $crate::__named_block_internal!(@value $lp $e)),) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren ($($tail)*) -> ($($out)* break $life2 $e,) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident $lp:tt $cb:tt $shadow:tt) $paren:tt (break $life2:tt $e:expr) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren () -> ($($out)* break $life2 $e) $stack)
+                    (@rewrite ($life1 $ret $lp $cb $shadow) $paren () -> ($($out)* $crate::__named_block_expand!(@break $life2 $ret $crate::__named_block_internal!(@value $lp $e))) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret $lp $cb $shadow) $paren () -> ($($out)* break $life2 $e) $stack)
+            }
+        }
+    };
+    // "continue LIFETIME EXPR" in a loop with state: a matching continue
+    // stores the new state and goes on with the next iteration, which binds
+    // it to the state pattern. A plain continue is left alone (so the state
+    // is reused, which only works if it's Copy).
+    (@rewrite ($life1:tt $ret:ident (loop $($state:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life1 $ret (loop $($state)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2;) $stack)
+    };
+    (@rewrite ($life1:tt $ret:ident (loop $($state:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt, $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life1 $ret (loop $($state)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2,) $stack)
+    };
+    (@rewrite ($life1:tt $ret:ident (loop $($state:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@rewrite ($life1 $ret (loop $($state)*) $cb $shadow) $paren () -> ($($out)* continue $life2) $stack)
+    };
+    (@rewrite ($life1:tt $ret:ident (loop $state:ident $($rest:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt $e:expr; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@continue_state $life1 $life2 $shadow
+            (@rewrite ($life1 $ret (loop $state $($rest)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2 $e;) $stack)
+            (@rewrite ($life1 $ret (loop $state $($rest)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* { $state = $e; continue $life2; }) $stack))
+    };
+    (@rewrite ($life1:tt $ret:ident (loop $state:ident $($rest:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt $e:expr, $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@continue_state $life1 $life2 $shadow
+            (@rewrite ($life1 $ret (loop $state $($rest)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2 $e,) $stack)
+            (@rewrite ($life1 $ret (loop $state $($rest)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* { $state = $e; continue $life2; },) $stack))
+    };
+    (@rewrite ($life1:tt $ret:ident (loop $state:ident $($rest:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt $e:expr) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_internal!(@continue_state $life1 $life2 $shadow
+            (@rewrite ($life1 $ret (loop $state $($rest)*) $cb $shadow) $paren () -> ($($out)* continue $life2 $e) $stack)
+            (@rewrite ($life1 $ret (loop $state $($rest)*) $cb $shadow) $paren () -> ($($out)* { $state = $e; continue $life2; }) $stack))
+    };
+    // utility: continue with the second set of arguments if the continue
+    // matches the block's label (and it isn't shadowed), otherwise the first
+    (@continue_state $life1:tt $life2:tt $shadow:tt $no:tt $yes:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow $no $yes)
+            } else {
+                $crate::__named_block_internal! $no
+            }
+        }
+    };
+
+    // "continue LIFETIME" in a `for` or `while` loop: the block's label is on the outer loop that
+    // produces the value, so a matching continue is sent to the inner loop's own label instead
+    (@rewrite ($life1:tt $ret:ident ($kw:ident $next:tt $($head:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2;) $stack)
+                    (@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $next;) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2;) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident ($kw:ident $next:tt $($head:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt, $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2,) $stack)
+                    (@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $next,) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2,) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident ($kw:ident $next:tt $($head:tt)*) $cb:tt $shadow:tt) $paren:tt (continue $life2:tt) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren () -> ($($out)* continue $life2) $stack)
+                    (@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren () -> ($($out)* continue $next) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret ($kw $next $($head)*) $cb $shadow) $paren () -> ($($out)* continue $life2) $stack)
+            }
+        }
+    };
+    // "continue LIFETIME": compare the lifetimes, if they match (and the label isn't shadowed) then error, otherwise leave it alone
+    // (this only applies to bare blocks)
+    (@rewrite ($life1:tt $ret:ident () $cb:tt $shadow:tt) $paren:tt (continue $life2:tt; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret () $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2;) $stack)
+                    (@rewrite ($life1 $ret () $cb $shadow) $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error NoMatchedContinueInNamedBlock);) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret () $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2;) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident () $cb:tt $shadow:tt) $paren:tt (continue $life2:tt, $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret () $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2,) $stack)
+                    (@rewrite ($life1 $ret () $cb $shadow) $paren ($($tail)*) -> ($($out)* $crate::__named_block_internal!(@error NoMatchedContinueInNamedBlock),) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret () $cb $shadow) $paren ($($tail)*) -> ($($out)* continue $life2,) $stack)
+            }
+        }
+    };
+    (@rewrite ($life1:tt $ret:ident () $cb:tt $shadow:tt) $paren:tt (continue $life2:tt) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $life1 == $life2 {
+                $crate::__named_block_internal!(@shadowed $life2 $shadow
+                    (@rewrite ($life1 $ret () $cb $shadow) $paren () -> ($($out)* continue $life2) $stack)
+                    (@rewrite ($life1 $ret () $cb $shadow) $paren () -> ($($out)* $crate::__named_block_internal!(@error NoMatchedContinueInNamedBlock)) $stack))
+            } else {
+                $crate::__named_block_internal!(@rewrite ($life1 $ret () $cb $shadow) $paren () -> ($($out)* continue $life2) $stack)
+            }
+        }
+    };
+
+    // The walker is about to skip a closure or an item. A break or continue
+    // to the block's own label in there can't reach the block, so it's
+    // reported here, before rustc produces a more confusing error. Macro
+    // calls (including nested block! calls) aren't looked into, and neither
+    // are loops that declare the label again.
+    (@skip ($life:tt $($state:tt)*) $kind:tt $input:tt $next:tt) => {
+        $crate::__named_block_internal!(@escapes $kind $life $input $next)
+    };
+    (@escapes item $life:tt (macro_rules ! $($tail:tt)*) ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@escapes $kind:tt $life:tt (macro_rules ! $name:ident $def:tt $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)
+    };
+    (@escapes $kind:tt $life:tt ($($name:ident)::+ ! $args:tt $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)
+    };
+    (@escapes $kind:tt $life:tt (break $label:lifetime $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_cond! {
+            if $life == $label {
+                $crate::__named_block_internal!(@error LabelEscapesIntoClosureOrItem)
+            } else {
+                $crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)
+            }
+        }
+    };
+    (@escapes $kind:tt $life:tt (continue $label:lifetime $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_cond! {
+            if $life == $label {
+                $crate::__named_block_internal!(@error LabelEscapesIntoClosureOrItem)
+            } else {
+                $crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)
+            }
+        }
+    };
+    // (a lifetime only declares a label in front of a loop or a block, so that bounds like
+    // `<'a: 'b>` in a generic item aren't mistaken for one)
+    (@escapes $kind:tt $life:tt ($label:lifetime : loop $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes_label $kind $life $label (loop $($tail)*) $next)
+    };
+    (@escapes $kind:tt $life:tt ($label:lifetime : while $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes_label $kind $life $label (while $($tail)*) $next)
+    };
+    (@escapes $kind:tt $life:tt ($label:lifetime : for $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes_label $kind $life $label (for $($tail)*) $next)
+    };
+    (@escapes $kind:tt $life:tt ($label:lifetime : { $($body:tt)* } $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes_label $kind $life $label ({ $($body)* } $($tail)*) $next)
+    };
+    (@escapes_label $kind:tt $life:tt $label:tt ($($tail:tt)*) $next:tt) => {
+        $crate::__named_block_cond! {
+            if $life == $label {
+                $crate::__named_block_internal!(@escapes_loop $kind $life ($($tail)*) $next)
+            } else {
+                $crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)
+            }
+        }
+    };
+    // (an item ends with its first brace group or `;`, a closure body with `,` or `;`)
+    (@escapes item $life:tt ({ $($body:tt)* } $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes body $life ($($body)*) $next)
+    };
+    (@escapes item $life:tt (; $($tail:tt)*) ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@escapes closure $life:tt (; $($tail:tt)*) ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@escapes closure $life:tt (, $($tail:tt)*) ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@escapes $kind:tt $life:tt ({ $($inner:tt)* } $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes body $life ($($inner)*)
+            ($crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)))
+    };
+    (@escapes $kind:tt $life:tt (( $($inner:tt)* ) $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes body $life ($($inner)*)
+            ($crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)))
+    };
+    (@escapes $kind:tt $life:tt ([ $($inner:tt)* ] $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes body $life ($($inner)*)
+            ($crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)))
+    };
+    (@escapes $kind:tt $life:tt ($head:tt $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)
+    };
+    (@escapes $kind:tt $life:tt () ($($next:tt)*)) => {
+        $($next)*
+    };
+    // (a loop that declares the label again: skip its header and body)
+    (@escapes_loop $kind:tt $life:tt ({ $($body:tt)* } $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes $kind $life ($($tail)*) $next)
+    };
+    (@escapes_loop $kind:tt $life:tt ($head:tt $($tail:tt)*) $next:tt) => {
+        $crate::__named_block_internal!(@escapes_loop $kind $life ($($tail)*) $next)
+    };
+    (@escapes_loop $kind:tt $life:tt () ($($next:tt)*)) => {
+        $($next)*
+    };
+
+    // everything else is up to the tree walker
+    (@rewrite $state:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_internal!) $state $paren $input -> $out $stack)
+    };
+}
+
+/// Implementation detail of `block!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_number {
+    // Rewriter for the tree walker (see walk.rs), used by @exit_index. STATE is ((N) (LABEL RET
+    // CALLBACK)): each @value call with (on index) gets the next index N, and then the walk
+    // goes on inside it, since the value may contain more breaks. When the walk is done, the
+    // output is wrapped as usual.
+    (@rewrite (($($n:tt)*) $cb:tt) $paren:tt (__named_block_internal ! (@value (on index) $($v:tt)*) $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_number!) (($($n)* + 1) $cb) $paren
+                                    ((@value (on index ($($n)*)) $($v)*) $($tail)*) -> ($($out)* __named_block_internal !) $stack)
+    };
+    (@rewrite $st:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_number!) $st $paren $input -> $out $stack)
+    };
+    (@skip $st:tt $kind:tt $input:tt ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@done ($n:tt ($life:tt $ret:ident $cb:tt)) ($($out:tt)*)) => {
+        $crate::__named_block_internal!(@wrap $life (on index) $ret $cb { $($out)* })
+    };
+}
+
+/// Provides the "early exit from any block" control-flow primitive that was mentioned in [RFC 243][link].
+///
+/// The macro can also be invoked by path, or imported with `use` in Rust 2018 code (Rust 1.30 or
+/// later):
+///
+/// ```edition2018
+/// use named_block::block as named;
+///
+/// # fn main() {
+/// let x = named!('a: {
+///     break 'a 0;
+///     1
+/// });
+/// let y = named_block::block!('b: loop {
+///     break 'b 1;
+/// });
+/// assert_eq!((x, y), (0, 1));
+/// # }
+/// ```
+///
+/// See README.md for more details.
+///
+/// [link]: https://github.com/rust-lang/rfcs/blob/master/text/0243-trait-based-exception-handling.md#early-exit-from-any-block
+///
+/// Syntax
+/// ======
+///
+/// The macro accepts these forms:
+///
+/// - `block!('label: { ... })` -- a block that can be exited early with `break 'label value`
+/// - `block!('label: EXPR)` -- a shorthand for `block!('label: { EXPR })`, for a body that is a
+///   single expression, like `if ... { ... } else { ... }` or a method chain
+/// - `block!('label: if COND { ... } else { ... })` and `block!('label: if let PAT = EXPR { ... }
+///   else { ... })` -- a conditional that can be left with `break 'label value` from anywhere in
+///   any of its branches (including `else if` ones); a branch that gets to the end produces the
+///   value of the `if`, and so of the block
+/// - `block!('label: match EXPR { ... })` -- a `match` whose arms can `break 'label value`
+///   (whether the arm ends with a comma, a block or nothing at all); an arm that doesn't break
+///   produces the value of the `match`, and so of the block
+/// - labeled blocks inside the body, like `'inner: { ... }`, work as usual (even on compilers
+///   without native labeled blocks, where they become nested `block!` calls, which may need a
+///   higher `recursion_limit`), and a `break 'label` inside them still leaves the outer block
+/// - `break!('label, value)` (or `break!('label)`) inside the body is the same as
+///   `break 'label value`, but the value is delimited, so it can start with a block or end with
+///   a comma without confusing the macro, and it's found even in the arguments of other macros
+///   (outside a block it's a syntax error, since `break` is a keyword)
+/// - `guard!('label, let PAT = EXPR else value);` inside the body is a `let ... else` whose `else`
+///   arm is `break 'label value` (Rust 1.65 and later)
+/// - `or_break!(OPTION, 'label, value)` inside the body unwraps `OPTION`, or does
+///   `break 'label value` if it's `None` (with no value, `break 'label`)
+/// - `ok_or_break!(RESULT, 'label)` inside the body unwraps `RESULT`, or does
+///   `break 'label Err(error)` (and `ok_or_break!(RESULT, 'label, MAP)` maps the error first)
+/// - `bail!('label)` inside the body is `break 'label Default::default()`
+/// - `break_if!('label, COND, value)` inside the body is `if COND { break 'label value }`
+/// - `ensure!('label, COND, value)` inside the body is `if !(COND) { break 'label value }`
+/// - `block!('label | 'alias: ...)` -- any of the forms here with more than one label, so that
+///   `break 'label` and `break 'alias` are the same thing
+/// - `block!('a: { ... }, 'b: ... , ...)` -- several blocks side by side, producing a tuple of
+///   their values (each block after the first can be any of the forms here)
+/// - `block!('label: stmt { ... });` -- a statement that runs the block and throws its value away,
+///   without a warning even if the value is `#[must_use]`
+/// - `block!('label: let PAT = { ... });` -- a statement that binds the value of the block to
+///   `PAT` (an irrefutable pattern, as in any `let`), like `let PAT = block!('label: { ... });`
+///   (the other arms of the bare block forms below can follow the body here, too)
+/// - `block!('label: -> TYPE { ... })` -- the same, with the type of the block's value spelled out,
+///   which helps type inference and error messages (this can also come before the other arms of
+///   the bare block forms below)
+/// - `block!('label: async { ... })` and `block!('label: async move { ... })` -- an async block
+///   (Rust 2018), where `break 'label value` finishes the future with `value` (`.await` works as
+///   usual in the body)
+/// - `block!('label: generator { ... })` and `block!('label: generator move { ... })` -- a
+///   generator (nightly only, with `#![feature(coroutines, stmt_expr_attributes)]`), where `yield`
+///   works as usual and `break 'label value` finishes it with `value`
+/// - `block!('label: |ARGS| { ... })`, `block!('label: move |ARGS| -> TYPE { ... })` and so on --
+///   a closure whose body can be left with `break 'label value`, which returns `value` from the
+///   closure
+/// - `block!('label: { ... } nobreak { ... })` -- the `nobreak` arm runs only if the body gets to
+///   the end without a `break 'label`, and its value is then the value of the block (so the body
+///   itself must have type `()`, like a loop body)
+/// - `block!('label: loop { ... })` -- a loop whose `break 'label value` statements produce a value
+/// - `block!('label: loop (PAT = INIT) { ... })` -- a loop with state: the state starts out as
+///   `INIT`, each iteration binds it to `PAT`, and `continue 'label EXPR` goes on to the next
+///   iteration with `EXPR` as the new state
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// // the 10th Fibonacci number
+/// let fib = block!('a: loop ((n, a, b) = (10, 0u64, 1u64)) {
+///     if n == 0 { break 'a a; }
+///     continue 'a (n - 1, b, a + b);
+/// });
+/// assert_eq!(fib, 55);
+/// # }
+/// ```
+///
+/// - `block!('label: loop (max_iter = N) { ... })` -- a loop that panics if it is about to go
+///   around for the `N+1`th time, or, with an `else { ... }` arm after the body, produces the value
+///   of that arm instead
+/// - `block!('label: loop (timeout = DURATION) { ... })` -- the same with a time limit (with the
+///   `std` feature): the loop panics, or produces the value of its `else` arm, if it is about to go
+///   around again when `DURATION` has passed since it started (the options can be combined, as in
+///   `loop (max_iter = N, timeout = DURATION)`, and whichever runs out first ends the loop)
+/// - `block!('label: loop (every = N => CALLBACK) { ... })` -- a loop that calls `CALLBACK` (a
+///   closure, or anything else that can be called with one argument) before every `N`th iteration,
+///   with the number of that iteration, for example to report progress or to feed a watchdog (this
+///   can be combined with the other options too; `N` is evaluated once, and must not be 0)
+/// - `block!('label: loop (yield = EXPR) { ... })` -- a loop that evaluates `EXPR` at the top of
+///   every iteration, to give other tasks a chance to run in a busy loop (`EXPR` could be
+///   `yield_now().await` in an async function, or a spin-loop hint)
+/// - `block!('label: retry { ... })` -- a block where `continue 'label` (which is an error in a
+///   plain block) starts the block over from the top, and getting to the end produces a value as
+///   usual
+/// - `block!('label: for PAT in EXPR { ... })` -- a `for` loop that produces `Some(value)` when
+///   `break 'label value` is taken, and `None` when it runs to completion (`continue 'label` goes
+///   on to the next iteration)
+/// - `block!('label: while COND { ... })` -- the same for a `while` loop, which produces `None`
+///   once `COND` is false
+/// - `block!('label: for PAT in EXPR { ... } else { ... })` and
+///   `block!('label: while COND { ... } else { ... })` -- as in Python, the `else` arm supplies the
+///   value when the loop runs to completion without a `break 'label`, so that the loop produces
+///   `value` instead of `Some(value)` (for `while let`, the `else` arm runs when the pattern stops
+///   matching)
+/// - `block!('label: { ... } on 'label (PAT) => EXPR, on fallthrough (PAT) => EXPR)` -- exit
+///   handlers: the value of a `break 'label` is matched against the first pattern, and the value of
+///   the body (when it gets to the end) against the second, and the matching `EXPR` gives the value
+///   of the block (either handler can be left out, to pass the value through unchanged)
+/// - `block!(@controlflow 'label: { ... })` -- a block that produces a
+///   [`ControlFlow`](https://doc.rust-lang.org/core/ops/enum.ControlFlow.html): `Break(value)` from
+///   a `break 'label value`, or `Continue(value)` with the value of the body (Rust 1.55 and later)
+/// - `block!(@option 'label: { ... })` -- a block that produces `Some(value)` from a
+///   `break 'label value`, or `None` if the body (which must have type `()`) gets to the end
+/// - `block!(@early 'label: { ... })` -- a block that produces `(value, exited_early)`, where
+///   `exited_early` is true if the value came from a `break 'label value`
+/// - `block!(@exit_index 'label: { ... })` -- a block that produces `(value, exit_index)`, where
+///   the `break 'label`s are numbered from 1 in the order they appear in the source, and
+///   `exit_index` is 0 if the body got to the end (this is meant for tests and tracing)
+/// - `block!('label: { ... } catch (PAT) { ... })` -- the body produces a `Result`, whether with
+///   `break 'label Err(error)` or as its value: `Ok(value)` gives `value`, and `Err(error)` runs
+///   the `catch` arm with `error` bound to `PAT` (an irrefutable pattern, as for a closure
+///   parameter), which gives the value of the block instead
+/// - any of the forms above followed by `finally { ... }` -- the `finally` arm runs after the block
+///   or loop has produced its value, whichever `break` was taken (but not on `return`, `?` or a
+///   panic), and then the value is returned
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let words = ["apple", "banana", "cherry"];
+/// let found = block!('a: for (i, w) in words.iter().enumerate() {
+///     if w.starts_with('b') { break 'a i; }
+/// });
+/// assert_eq!(found, Some(1));
+/// # }
+/// ```
+///
+/// ```edition2018
+/// # use std::future::Future;
+/// # use std::pin::Pin;
+/// # use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+/// # fn block_on<F: Future>(mut f: F) -> F::Output {
+/// #     fn raw() -> RawWaker { RawWaker::new(std::ptr::null(), &VTABLE) }
+/// #     static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw(), |_| (), |_| (), |_| ());
+/// #     let waker = unsafe { Waker::from_raw(raw()) };
+/// #     let mut f = unsafe { Pin::new_unchecked(&mut f) };
+/// #     loop {
+/// #         let mut cx = Context::from_waker(&waker);
+/// #         if let Poll::Ready(v) = f.as_mut().poll(&mut cx) { return v; }
+/// #     }
+/// # }
+/// use named_block::block;
+///
+/// async fn fetch(key: u32) -> Option<u32> {
+///     if key < 10 { Some(key * 2) } else { None }
+/// }
+///
+/// # fn main() {
+/// let sum = |keys: Vec<u32>| block!('a: async move {
+///     let mut sum = 0;
+///     for key in keys {
+///         match fetch(key).await {
+///             Some(value) => sum += value,
+///             None => break 'a Err(key),
+///         }
+///     }
+///     Ok(sum)
+/// });
+/// assert_eq!(block_on(sum(vec![1, 2, 3])), Ok(12));
+/// assert_eq!(block_on(sum(vec![1, 20, 3])), Err(20));
+/// # }
+/// ```
+///
+/// The body of a bare block can also be divided into sections by labels at its top level (a
+/// `'name:` that isn't followed by a loop or a block). `goto 'name` jumps forward to a section,
+/// skipping the code in between, and a section that runs to the end goes on to the next one.
+/// This is meant for cleanup paths, as with `goto` in C. The sections are lowered onto a loop with
+/// state, so a `let` binding is only visible in its own section.
+///
+/// ```
+/// # #![recursion_limit = "1000"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let mut log = Vec::new();
+/// let n = block!('a: {
+///     log.push("open");
+///     if log.len() < 2 { goto 'close; }
+///     log.push("read");
+///     'close:
+///     log.push("close");
+///     log.len()
+/// });
+/// assert_eq!((n, log), (2, vec!["open", "close"]));
+/// # }
+/// ```
+///
+/// A `goto` can only jump within the innermost `block!` (or `state_machine!`) around it. A
+/// `goto` to an earlier section is rejected with `BackwardGotoInNamedBlock`, and a `goto` to a
+/// section that doesn't exist with `UnknownStateInGoto`:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = block!('a: {
+///     'again:
+///     goto 'again;
+/// });
+/// # }
+/// ```
+///
+/// Anything else is rejected with a compile error whose message describes the problem, such as
+/// `MissingLabelInNamedBlockExpectedLabelColonBraces`:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = block!({
+///     1
+/// });
+/// # }
+/// ```
+///
+/// Declaring the block's own label again inside it, on a loop or a nested `block!` call, is
+/// rejected with `DuplicateLabelInNamedBlock`, unless the loop or call is marked with
+/// `#[block(shadow)]`:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = block!('a: {
+///     'a: loop { break 'a; }
+///     1
+/// });
+/// # }
+/// ```
+///
+/// A `break` or `continue` to the block's label inside a closure or a nested item can't reach the
+/// block, so it's rejected with `LabelEscapesIntoClosureOrItem`:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = block!('a: {
+///     let f = |x: i32| if x > 0 { break 'a x; } else { x };
+///     f(1)
+/// });
+/// # }
+/// ```
+///
+/// To leave the closure early instead, give the closure a label of its own with the closure form,
+/// where a break returns from the closure:
+///
+/// ```
+/// # #![recursion_limit = "256"]
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = block!('a: {
+///     let f = block!('f: |x: i32| if x > 0 { break 'f x } else { -x });
+///     if f(-1) > 0 { break 'a 0; }
+///     f(2)
+/// });
+/// assert_eq!(x, 0);
+/// # }
+/// ```
+///
+/// Macro protocol
+/// ==============
+///
+/// Other macros that want to start the scanner with a pre-built state can call the versioned
+/// entry point `block!(@v1 ...)`. Its argument layout is covered by semver: it will keep being
+/// accepted, with the same meaning, for as long as the crate's major version doesn't change.
+/// (The other `@` rules, which live in a hidden helper macro, can change at any time.)
+///
+/// ```text
+/// block!(@v1 PAREN LABEL RET (INPUT...) -> (OUTPUT...) (STACK LOOP INIT))
+/// ```
+///
+/// - `PAREN`: the bracket type surrounding the code being scanned, one of `{}`, `()` or `[]`
+///   (use `{}` at the top level)
+/// - `LABEL`: the block label, e.g. `'a`
+/// - `RET`: the identifier used for the block's return slot
+/// - `INPUT`: code that still needs to be transformed
+/// - `OUTPUT`: code that has already been transformed (it is emitted verbatim)
+/// - `STACK`: the tree walker stack, either `()` at the top level or
+///   `(PAREN (INPUT...) -> (OUTPUT...) STACK)` for each enclosing token tree that is being
+///   scanned, innermost first
+/// - `LOOP`: `()` for a bare block or `(loop)` for a loop
+/// - `INIT`: ignored (it was needed by the expansion for compilers without `break` with a value,
+///   which are no longer supported); pass `()`
+///
+/// To post-process the expansion instead, use the callback form, which accepts the same syntax as
+/// `block!` after the callback macro and a single token tree of extra arguments:
+///
+/// ```text
+/// block!(@callback (MACRO!) (EXTRA...) 'label: { ... })
+/// block!(@callback (MACRO!) (EXTRA...) 'label: { ... } nobreak { ... })
+/// block!(@callback (MACRO!) (EXTRA...) 'label: { ... } on ...)
+/// block!(@callback (MACRO!) (EXTRA...) 'label: loop { ... })
+/// block!(@callback (MACRO!) (EXTRA...) 'label: loop (PAT = INIT) { ... })
+/// block!(@callback (MACRO!) (EXTRA...) 'label: for PAT in EXPR { ... })
+/// block!(@callback (MACRO!) (EXTRA...) 'label: for PAT in EXPR { ... } else { ... })
+/// block!(@callback (MACRO!) (EXTRA...) 'label: while COND { ... })
+/// block!(@callback (MACRO!) (EXTRA...) 'label: while COND { ... } else { ... })
+/// ```
+///
+/// Instead of expanding to the transformed block, this expands to `MACRO!((EXTRA...) { ... })`,
+/// where the second argument is the complete expansion (an expression in braces).
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// macro_rules! doubled {
+///     (() $e:expr) => { $e * 2 }
+/// }
+///
+/// # fn main() {
+/// let x = block!(@callback (doubled!) () 'a: {
+///     break 'a 21;
+/// });
+/// assert_eq!(x, 42);
+/// # }
+/// ```
+///
+/// So `block!('a: { ... })` is the same as `block!(@v1 {} 'a _ret (...) -> () (() () ()))`.
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = block!(@v1 {} 'a ret (if y > 2 { break 'a 1; } y) -> (let y = 3;) (() () ()));
+/// assert_eq!(x, 1);
+/// # }
+/// ```
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = block!('a: {
+///     break 'a 0;
+///     1
+/// });
+/// assert_eq!(x, 0);
+/// # }
+/// ```
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// assert_eq!(
+///     42,
+///     block!('a: {
+///         enum Foo { Bar(i32) }
+///         let closure = #[block(ignore)] {
+///             move |Foo::Bar(x): Foo| -> i32 {
+///                 x + block!('a: {
+///                     break 'a 41;
+///                 })
+///             }
+///         };
+///     
+///         closure(Foo::Bar(1))
+///     })
+/// );
+/// # }
+/// ```
+///
+/// Plain code costs about one level of recursion per token, so a body of twenty short statements
+/// still fits in the default `recursion_limit`:
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let mut x = 0;
+/// let y = block!('a: {
+///     x += 1; x += 2; x += 3; x += 4; x += 5;
+///     x += 6; x += 7; x += 8; x += 9; x += 10;
+///     x += 11; x += 12; x += 13; x += 14; x += 15;
+///     x += 16; x += 17; x += 18; x += 19; x += 20;
+///     if x > 100 { break 'a x; }
+///     0
+/// });
+/// assert_eq!(y, 210);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! block {
+    // entry point for bare block (the body may be split into sections, see @body)
+    ($life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@body $life () ($($body)*))
+    };
+
+    // entry point for bare block with a result type, which is given to a `let` around the whole
+    // thing, so that the break values and the body are checked against it
+    ($life:tt: -> $t:ty { $($body:tt)* } $($rest:tt)*) => {{
+        let value: $t = $crate::block!($life: { $($body)* } $($rest)*);
+        value
+    }};
+
+    // entry point for the statement form, which binds the value of a bare block to a pattern
+    ($life:tt: let $pat:pat = { $($body:tt)* } $($rest:tt)*) => {
+        let $pat = $crate::block!($life: { $($body)* } $($rest)*);
+    };
+
+    // entry points for a block with more than one label (see @alias), also after the forms that
+    // start with `@`
+    ($life:tt | $($rest:tt)+) => {
+        $crate::__named_block_internal!(@aliases () $life () ($($rest)+))
+    };
+    (@controlflow $life:tt | $($rest:tt)+) => {
+        $crate::__named_block_internal!(@aliases (@controlflow) $life () ($($rest)+))
+    };
+    (@option $life:tt | $($rest:tt)+) => {
+        $crate::__named_block_internal!(@aliases (@option) $life () ($($rest)+))
+    };
+    (@early $life:tt | $($rest:tt)+) => {
+        $crate::__named_block_internal!(@aliases (@early) $life () ($($rest)+))
+    };
+    (@exit_index $life:tt | $($rest:tt)+) => {
+        $crate::__named_block_internal!(@aliases (@exit_index) $life () ($($rest)+))
+    };
+
+    // entry point for several sibling blocks, which produce a tuple (see @siblings)
+    ($life:tt: { $($body:tt)* }, $($rest:tt)+) => {
+        $crate::__named_block_internal!(@siblings (($life: { $($body)* })) () ($($rest)+))
+    };
+
+    // entry point for the statement form that throws the value away, for a block that is only
+    // there for the control flow (`let _` keeps the value from triggering unused/must_use lints)
+    ($life:tt: stmt { $($body:tt)* } $($rest:tt)*) => {
+        let _ = $crate::block!($life: { $($body)* } $($rest)*);
+    };
+
+    // entry point for bare block with a nobreak arm, which comes after the body in the same block
+    ($life:tt: { $($body:tt)* } nobreak { $($alt:tt)* }) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret () () ()) {} ({ $($body)* } $($alt)*) -> () ())
+    };
+
+    // entry point for bare block with exit handlers (see @exit_handlers)
+    ($life:tt: { $($body:tt)* } on $($handlers:tt)+) => {
+        $crate::__named_block_internal!(@exit_handlers $life () ((value) (value)) ((value) (value)) ($($body)*) (on $($handlers)+))
+    };
+
+    // entry point for a block that produces a ControlFlow (Rust 1.55), which is a block with exit
+    // handlers that wrap each kind of exit
+    (@controlflow $life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@exit_handlers $life ()
+            ((value) ($crate::__NamedBlockControlFlow::Break(value)))
+            ((value) ($crate::__NamedBlockControlFlow::Continue(value)))
+            ($($body)*) ())
+    };
+
+    // entry point for a block that produces Some(value) from a break, or None if the body (which
+    // must have type `()`) gets to the end
+    (@option $life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@exit_handlers $life ()
+            ((value) (Some(value)))
+            ((()) (None))
+            ($($body)*) ())
+    };
+
+    // entry point for a block that produces (value, exited_early): the discriminant of the exit
+    // handlers serves as the flag
+    (@early $life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@exit_handlers $life ()
+            ((value) ((value, true)))
+            ((value) ((value, false)))
+            ($($body)*) ())
+    };
+
+    // entry point for a block that produces (value, exit_index): the matching breaks are numbered
+    // from 1 in source order, and 0 means that the body got to the end
+    (@exit_index $life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@exit_index $life () ($($body)*))
+    };
+
+    // entry points with a catch arm, which handles the block's value through a callback (see
+    // @catch), optionally followed by a finally arm
+    ($life:tt: { $($body:tt)* } catch ($($pat:tt)*) { $($handler:tt)* }) => {
+        $crate::block!(@callback ($crate::__named_block_internal!) (@catch () ($($pat)*) ($($handler)*)) $life: { $($body)* })
+    };
+    ($life:tt: { $($body:tt)* } catch ($($pat:tt)*) { $($handler:tt)* } finally { $($fin:tt)* }) => {
+        $crate::block!(@callback ($crate::__named_block_internal!)
+                       (@catch (($crate::__named_block_internal!) (@finally () ($($fin)*))) ($($pat)*) ($($handler)*))
+                       $life: { $($body)* })
+    };
+
+    // entry points with a finally arm: the block is expanded through a callback that runs the arm
+    // afterwards (see @finally)
+    ($life:tt: { $($body:tt)* } finally { $($fin:tt)* }) => {
+        $crate::block!(@callback ($crate::__named_block_internal!) (@finally () ($($fin)*)) $life: { $($body)* })
+    };
+    ($life:tt: { $($body:tt)* } nobreak { $($alt:tt)* } finally { $($fin:tt)* }) => {
+        $crate::block!(@callback ($crate::__named_block_internal!) (@finally () ($($fin)*)) $life: { $($body)* } nobreak { $($alt)* })
+    };
+    ($life:tt: loop { $($body:tt)* } finally { $($fin:tt)* }) => {
+        $crate::block!(@callback ($crate::__named_block_internal!) (@finally () ($($fin)*)) $life: loop { $($body)* })
+    };
+    ($life:tt: loop ($($state:tt)*) { $($body:tt)* } finally { $($fin:tt)* }) => {
+        $crate::block!(@callback ($crate::__named_block_internal!) (@finally () ($($fin)*)) $life: loop ($($state)*) { $($body)* })
+    };
+
+    // entry point for loop
+    ($life:tt: loop { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret (loop) () ()) {} ($($body)*) -> () ())
+    };
+
+    // entry points for loop with options (see @loop_options), which would otherwise look like a
+    // loop with state
+    ($life:tt: loop ($opt:tt = $($opts:tt)*) { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@loop_option $opt
+            (@loop_options $life () () () ($opt = $($opts)*,) ($($body)*))
+            (@loop_state $life () () ($opt = $($opts)*) ($($body)*)))
+    };
+    ($life:tt: loop ($opt:tt = $($opts:tt)*) { $($body:tt)* } else { $($alt:tt)* }) => {
+        $crate::__named_block_internal!(@loop_option $opt
+            (@loop_options $life ({ $($alt)* }) () () ($opt = $($opts)*,) ($($body)*))
+            (@error ExpectedBracesOrLoopBracesAfterLabelInNamedBlock))
+    };
+
+    // entry point for loop with state
+    ($life:tt: loop ($($state:tt)*) { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@loop_state $life () () ($($state)*) ($($body)*))
+    };
+
+    // entry point for a block that can start over: it's a loop whose body breaks out with its own
+    // value, so `continue 'a` goes back to the top
+    ($life:tt: retry { $($body:tt)* }) => {
+        $crate::block!($life: loop {
+            #[allow(unreachable_code)] { break $life { $($body)* }; }
+        })
+    };
+
+    // entry points for `for` and `while` loops
+    ($life:tt: for $($rest:tt)*) => {
+        $crate::__named_block_internal!(@loop_entry for $life () () $($rest)*)
+    };
+    ($life:tt: while $($rest:tt)*) => {
+        $crate::__named_block_internal!(@loop_entry while $life () () $($rest)*)
+    };
+
+    // entry points for generators (nightly only, with the coroutines and stmt_expr_attributes
+    // features): the label goes on a block inside the closure, so a break returns from it, and
+    // `yield` passes through like any other expression
+    ($life:tt: generator { $($body:tt)* }) => {
+        #[coroutine] || { $crate::block!($life: { $($body)* }) }
+    };
+    ($life:tt: generator move { $($body:tt)* }) => {
+        #[coroutine] move || { $crate::block!($life: { $($body)* }) }
+    };
+
+    // entry points for closures: a break to the label returns from the closure (see @closure)
+    ($life:tt: || $($rest:tt)+) => {
+        $crate::__named_block_internal!(@closure $life () () ($($rest)+))
+    };
+    ($life:tt: move || $($rest:tt)+) => {
+        $crate::__named_block_internal!(@closure $life (move) () ($($rest)+))
+    };
+    ($life:tt: | $($rest:tt)+) => {
+        $crate::__named_block_internal!(@closure_args $life () () ($($rest)+))
+    };
+    ($life:tt: move | $($rest:tt)+) => {
+        $crate::__named_block_internal!(@closure_args $life (move) () ($($rest)+))
+    };
+
+    // entry points for async blocks (Rust 2018): the label goes on a block inside, so a break
+    // finishes the future (`async` is passed on as it was found, see @if_async in walk.rs, and
+    // anything else in front of a block goes to the brace-less form below)
+    ($life:tt: $async:ident { $($body:tt)* }) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($async { $crate::block!($life: { $($body)* }) })
+            ($crate::block!($life: { $async { $($body)* } })))
+    };
+    ($life:tt: $async:ident move { $($body:tt)* }) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($async move { $crate::block!($life: { $($body)* }) })
+            ($crate::block!($life: { $async move { $($body)* } })))
+    };
+
+    // entry points that pass the expansion to another macro: see "Macro protocol" in the docs above
+    (@callback ($($mac:tt)*) $extra:tt $life:tt: { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@body $life (($($mac)*) $extra) ($($body)*))
+    };
+    (@callback ($($mac:tt)*) $extra:tt $life:tt: { $($body:tt)* } nobreak { $($alt:tt)* }) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret () (($($mac)*) $extra) ()) {} ({ $($body)* } $($alt)*) -> () ())
+    };
+    (@callback ($($mac:tt)*) $extra:tt $life:tt: { $($body:tt)* } on $($handlers:tt)+) => {
+        $crate::__named_block_internal!(@exit_handlers $life (($($mac)*) $extra) ((value) (value)) ((value) (value)) ($($body)*) (on $($handlers)+))
+    };
+    (@callback ($($mac:tt)*) $extra:tt $life:tt: loop { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@rewrite ($life _ret (loop) (($($mac)*) $extra) ()) {} ($($body)*) -> () ())
+    };
+    (@callback ($($mac:tt)*) $extra:tt $life:tt: loop ($($state:tt)*) { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@loop_state $life (($($mac)*) $extra) () ($($state)*) ($($body)*))
+    };
+    (@callback ($($mac:tt)*) $extra:tt $life:tt: for $($rest:tt)*) => {
+        $crate::__named_block_internal!(@loop_entry for $life (($($mac)*) $extra) () $($rest)*)
+    };
+    (@callback ($($mac:tt)*) $extra:tt $life:tt: while $($rest:tt)*) => {
+        $crate::__named_block_internal!(@loop_entry while $life (($($mac)*) $extra) () $($rest)*)
+    };
+
+    // versioned entry point for other macros: see "Macro protocol" in the docs above
+    // (the argument layout of @v1 must never change -- if the scanner changes, translate here)
+    (@v1 $paren:tt $life:tt $ret:ident $input:tt -> $output:tt ($stack:tt $lp:tt $init:tt)) => {
+        $crate::__named_block_internal!(@rewrite ($life $ret $lp () ()) $paren $input -> $output $stack)
+    };
+
+    // The remaining rules catch malformed invocations and turn them into an error that names the
+    // problem, instead of rustc's "no rules expected this token".
+
+    // forgot the label
+    ({ $($body:tt)* }) => {
+        $crate::__named_block_internal!(@error MissingLabelInNamedBlockExpectedLabelColonBraces)
+    };
+    (loop { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@error MissingLabelInNamedBlockExpectedLabelColonLoopBraces)
+    };
+    // forgot the colon
+    ($life:tt { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@error MissingColonAfterLabelInNamedBlock)
+    };
+    ($life:tt loop { $($body:tt)* }) => {
+        $crate::__named_block_internal!(@error MissingColonAfterLabelInNamedBlock)
+    };
+    // unexpected tokens after the braces or after `loop`, or no body at all
+    ($life:tt: { $($body:tt)* } $($rest:tt)+) => {
+        $crate::__named_block_internal!(@error ExpectedBracesOrLoopBracesAfterLabelInNamedBlock)
+    };
+    ($life:tt: loop $($rest:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedBracesOrLoopBracesAfterLabelInNamedBlock)
+    };
+    ($life:tt:) => {
+        $crate::__named_block_internal!(@error ExpectedBracesOrLoopBracesAfterLabelInNamedBlock)
+    };
+    // no braces at all: the body is a single expression, so it can be put in braces
+    ($life:tt: $($body:tt)+) => {
+        $crate::block!($life: { $($body)+ })
+    };
+    // anything else
+    ($($other:tt)*) => {
+        $crate::__named_block_internal!(@error ExpectedLabelColonBracesOrLabelColonLoopBracesInNamedBlock)
+    };
+}
 
 #[cfg(test)]
 mod tests {
     #[test]
-    fn it_works() {
+    fn it_works() {
+        let flag = true;
+        let x = block!('a: {
+            if flag { break 'a "early exit"; }
+            "normal exit"
+        });
+        assert_eq!(x, "early exit");
+    }
+
+    #[test]
+    fn shadowing() {
+        let flag = false;
+        let x = block!('b: {
+            if flag { break 'b "early exit"; }
+            let _y = block!('c: {
+                if flag { break 'b "inner early exit"; };
+                String::from("inner normal exit")
+            });
+
+            #[block(ignore)]
+            {
+                #[allow(dead_code)]
+                fn f() -> i32 {
+                    block!('b: {
+                        break 'b 42;
+                    })
+                }
+            }
+            // items keep the user's spans, so clippy sees the loops below
+            #[allow(dead_code, clippy::never_loop)]
+            fn g() {
+                block!('b: {
+                    break 'b 42;
+                });
+
+                while false {
+                    break;
+                }
+                while false {
+                    continue;
+                }
+                'b: while false {
+                    continue 'b;
+                }
+            }
+
+            enum Foo { Bar(i32) }
+            let closure = move |Foo::Bar(x): Foo| -> i32 {
+                x + block!('d: {
+                    break 'd 42;
+                })
+            };
+            assert_eq!(closure(Foo::Bar(0)), 42);
+
+            "normal exit"
+        });
+        assert_eq!(x, "normal exit");
+
+        'e: for i in 1..5 {
+            assert!((1..5).contains(&i));
+            block!('d: {
+                //continue; //~ERROR NoBareContinueInNamedBlock
+                //continue 'd; //~ERROR NoMatchedContinueInNamedBlock
+                continue 'e;
+            });
+        }
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(42, block!('a: loop { break 'a 42 }));
+        assert_eq!((), block!('a: {})); // make sure it works with no breaks
+
+        let mut v = vec![];
+        let mut i = 0;
+        block!('a: loop {
+            i += 1;
+            if i == 5 {
+                continue 'a;
+            } else if i == 10 {
+                break 'a;
+            }
+            v.push(i);
+        });
+        assert_eq!(&*v, &[1, 2, 3, 4, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn protocol_v1() {
+        // resume scanning in the middle of an `if` body
+        let x = block!(@v1 {} 'a _ret (break 'a 1) -> ()
+                       (({} (2) -> (if true) ()) () ()));
+        assert_eq!(x, 1);
+
+        let mut i = 0;
+        block!(@v1 {} 'a _ret (i += 1; if i == 3 { break 'a; }) -> () (() (loop) (= ())));
+        assert_eq!(i, 3);
+    }
+
+    #[test]
+    fn shadowed_labels() {
+        // the inner loop has the same label as the block (which has to be allowed with
+        // #[block(shadow)]), so its breaks belong to the loop
+        let x = block!('a: {
+            let mut i = 0;
+            let n = #[block(shadow)] 'a: loop {
+                i += 1;
+                if i < 3 { continue 'a; }
+                break 'a i * 10;
+            };
+            if n == 30 { break 'a n + 1; }
+            0
+        });
+        assert_eq!(x, 31);
+
+        let y = block!('a: {
+            let mut sum = 0;
+            #[block(shadow)]
+            'a: for i in 0..10 {
+                if i % 2 == 0 { continue 'a; }
+                if i > 6 { break 'a; }
+                sum += i;
+            }
+            let mut j = 0;
+            #[block(shadow)]
+            'a: while j < 100 {
+                j += 1;
+                if j == 5 { break 'a; }
+            }
+            if sum == 9 { break 'a sum + j; }
+            0
+        });
+        assert_eq!(y, 14);
+
+        let z = block!('a: {
+            let n = #[block(shadow)] 'a: {
+                if y > 1 { break 'a y * 2; }
+                y
+            };
+            n + 1
+        });
+        assert_eq!(z, 29);
+
+        // a nested call can reuse the label too, and then its breaks belong to it
+        let w = block!('a: {
+            let inner = #[block(shadow)] block!('a: {
+                if z > 1 { break 'a 1; }
+                2
+            });
+            inner + 10
+        });
+        assert_eq!(w, 11);
+    }
+
+    #[test]
+    fn native_labeled_blocks() {
+        // breaks to a native labeled block inside the macro are left alone
+        let flag = true;
+        let x = block!('a: {
+            let n = 'x: {
+                if flag { break 'x 5; }
+                if !flag { break 'a 1; }
+                0
+            };
+            let m = 'y: {
+                if n > 1 { break 'y n * 2; }
+                n
+            };
+            m + 1
+        });
+        assert_eq!(x, 11);
+    }
+
+    #[test]
+    fn let_else() {
+        fn first_even(v: &[i32], fallback: Option<i32>) -> i32 {
+            block!('a: {
+                let Some(&x) = v.iter().find(|&&x| x % 2 == 0) else {
+                    let Some(f) = fallback else { break 'a -1; };
+                    break 'a f;
+                };
+                // (patterns are copied over without being walked)
+                let (Ok(y) | Err(y)) = if x > 10 { Ok::<i32, i32>(x) } else { Err(x) };
+                y * 2
+            })
+        }
+        assert_eq!(first_even(&[1, 4, 5], None), 8);
+        assert_eq!(first_even(&[1, 3], Some(7)), 7);
+        assert_eq!(first_even(&[1, 3], None), -1);
+    }
+
+    #[test]
+    fn loop_state() {
+        // Euclid's algorithm
+        let x = block!('a: loop ((a, b) = (1071, 462)) {
+            if b == 0 { break 'a a; }
+            continue 'a (b, a % b);
+        });
+        assert_eq!(x, 21);
+
+        // the state can be moved in and out
+        let v = block!('a: loop ((mut acc, i) = (Vec::new(), 0)) {
+            match i {
+                3 => break 'a acc,
+                _ => { acc.push(i); continue 'a (acc, i + 1) }
+            }
+        });
+        assert_eq!(v, [0, 1, 2]);
+
+        // a plain continue reuses the state (so it has to be Copy)
+        let mut tries = 0;
+        let y = block!('a: loop (n = 5) {
+            tries += 1;
+            if tries < 3 { continue 'a; }
+            if n == 0 { break 'a tries; }
+            continue 'a n - 1;
+        });
+        assert_eq!(y, 8);
+    }
+
+    #[test]
+    fn loop_match() {
+        enum Light { Red, Green, Yellow }
+
+        // count the transitions until the light is red for the third time
+        let mut reds = 0;
+        let steps = loop_match!('a: match (Light::Green, 0) {
+            (Light::Red, n) => {
+                reds += 1;
+                if reds == 3 { break 'a n; }
+                continue 'a (Light::Green, n + 1);
+            }
+            (Light::Green, n) => continue 'a (Light::Yellow, n + 1),
+            (Light::Yellow, n) => continue 'a (Light::Red, n + 1),
+        });
+        assert_eq!(steps, 8);
+    }
+
+    #[test]
+    fn sections() {
+        fn run(x: i32, log: &mut Vec<&'static str>) -> i32 {
+            block!('a: {
+                log.push("start");
+                if x < 0 { goto 'cleanup; }
+                if x == 0 { break 'a 0; }
+                for _ in 0..x {
+                    if x > 10 { goto 'done; }
+                }
+                log.push("work");
+                'cleanup:
+                log.push("cleanup");
+                'done:
+                log.push("done");
+                x
+            })
+        }
+
+        let mut log = Vec::new();
+        assert_eq!(run(-1, &mut log), -1);
+        assert_eq!(log, ["start", "cleanup", "done"]);
+        log.clear();
+        assert_eq!(run(0, &mut log), 0);
+        assert_eq!(log, ["start"]);
+        log.clear();
+        assert_eq!(run(5, &mut log), 5);
+        assert_eq!(log, ["start", "work", "cleanup", "done"]);
+        log.clear();
+        assert_eq!(run(20, &mut log), 20);
+        assert_eq!(log, ["start", "done"]);
+
+        // a section at the very end, and a nested block with its own sections
+        let mut n = 0;
+        block!('a: {
+            n += block!('b: {
+                goto 'skip;
+                'skip:
+                10
+            });
+            if n > 5 { goto 'end; }
+            n = 100;
+            'end:
+        });
+        assert_eq!(n, 10);
+
+        // a labeled loop ahead of the first section
+        let mut i = 0;
+        let z = block!('a: {
+            'l: while i < 5 { i += 1; if i == 2 { break 'l; } }
+            if i == 2 { goto 'two; }
+            i = 0;
+            'two:
+            i * 10
+        });
+        assert_eq!(z, 20);
+
+        // labels that aren't sections
+        let y = block!('a: {
+            fn pick<'x: 'y, 'y>(a: &'x i32, _: &'y i32) -> &'y i32 { a }
+            let v = 'l: loop { break 'l 1; };
+            *pick(&v, &2)
+        });
+        assert_eq!(y, 1);
+    }
+
+    #[test]
+    fn switch() {
+        fn run(n: u32, log: &mut Vec<&'static str>) -> i32 {
+            switch!('s: match n {
+                0 => 'zero: {
+                    log.push("zero");
+                    fallthrough 'one;
+                }
+                1 | 2 => 'one: {
+                    log.push("one");
+                    if n == 2 { fallthrough 'many; }
+                    1
+                },
+                x if x > 100 => 'big: {
+                    log.push("big");
+                    break 's -1;
+                },
+                _ => 'many: {
+                    log.push("many");
+                    2
+                }
+            })
+        }
+
+        let mut log = Vec::new();
+        assert_eq!(run(0, &mut log), 1);
+        assert_eq!(log, ["zero", "one"]);
+        log.clear();
+        assert_eq!(run(2, &mut log), 2);
+        assert_eq!(log, ["one", "many"]);
+        log.clear();
+        assert_eq!(run(200, &mut log), -1);
+        assert_eq!(log, ["big"]);
+        log.clear();
+        assert_eq!(run(5, &mut log), 2);
+        assert_eq!(log, ["many"]);
+    }
+
+    #[test]
+    fn state_machine() {
+        // a body that runs to the end goes on to the next state, and the last one gives the value
+        let mut trace = Vec::new();
+        let x = state_machine!('exit: {
+            'init: { trace.push("init"); }
+            'work: {
+                trace.push("work");
+                if trace.len() < 4 { goto 'work; }
+                if trace.len() > 10 { break 'exit 0; }
+            }
+            'last: { trace.len() }
+        });
+        assert_eq!(x, 4);
+        assert_eq!(trace, ["init", "work", "work", "work"]);
+
+        // a nested state machine has its own states
+        let mut n = 0;
+        let y = state_machine!('outer: {
+            'a: {
+                n += state_machine!('inner: {
+                    'a: { goto 'b; }
+                    'b: { 10 }
+                });
+                if n < 30 { goto 'a; }
+                if n > 100 { break 'outer 0; }
+            }
+            'b: { n + 1 }
+        });
+        assert_eq!(y, 31);
+    }
+
+    #[test]
+    fn for_loops() {
+        // the first even number that isn't a square
+        let v = [3, 4, 5, 12, 7];
+        let x = block!('a: for (i, &n) in v.iter().enumerate() {
+            if n % 2 == 1 { continue 'a; }
+            for d in 2..n {
+                // `continue 'a` from an inner loop goes on with the outer `for`
+                if d * d == n { continue 'a; }
+            }
+            break 'a (i, n);
+        });
+        assert_eq!(x, Some((3, 12)));
+
+        // running to completion gives None
+        let mut seen = 0;
+        let y = block!('a: for n in 0..5 {
+            seen += n;
+            if n > 10 { break 'a; }
+        });
+        assert_eq!((y, seen), (None, 10));
+
+        // `break 'a match ...` and friends are walked too
+        let z = block!('a: for n in 1.. {
+            if n * n > 50 {
+                break 'a match n { 8 => "eight", _ => "other" };
+            }
+        });
+        assert_eq!(z, Some("eight"));
+    }
+
+    #[test]
+    fn nobreak() {
+        fn check(v: &[i32]) -> (&'static str, usize) {
+            let mut checked = 0;
+            let r = block!('a: {
+                for &n in v {
+                    if n < 0 { break 'a "negative"; }
+                    checked += 1;
+                }
+                if checked > 3 { break 'a "too long"; }
+            } nobreak {
+                // only if the body got to the end
+                if checked == 0 { break 'a "empty"; }
+                "ok"
+            });
+            (r, checked)
+        }
+        assert_eq!(check(&[1, 2, 3]), ("ok", 3));
+        assert_eq!(check(&[1, -2, 3]), ("negative", 1));
+        assert_eq!(check(&[1, 2, 3, 4]), ("too long", 4));
+        assert_eq!(check(&[]), ("empty", 0));
+    }
+
+    #[test]
+    fn finally() {
+        let mut runs = 0;
+        let mut f = |n: i32| block!('a: {
+            if n < 0 { break 'a "negative"; }
+            if n == 0 { break 'a "zero"; }
+            "positive"
+        } finally {
+            runs += 1;
+        });
+        assert_eq!((f(-1), f(0), f(1)), ("negative", "zero", "positive"));
+        assert_eq!(runs, 3);
+
+        // after the nobreak arm
+        let mut log = Vec::new();
+        let x = block!('a: {
+            log.push("body");
+        } nobreak {
+            log.push("nobreak");
+            1
+        } finally {
+            log.push("finally");
+        });
+        assert_eq!((x, log), (1, vec!["body", "nobreak", "finally"]));
+
+        // and with each kind of loop
+        let mut runs = 0;
+        let a = block!('a: loop { break 'a 1; } finally { runs += 1; });
+        let b = block!('a: loop (n = 0) {
+            if n == 3 { break 'a n; }
+            continue 'a n + 1;
+        } finally { runs += 1; });
+        let c = block!('a: for n in 0..10 {
+            if n == 4 { break 'a n; }
+        } finally { runs += 1; });
+        let mut i = 0;
+        let d = block!('a: while i < 5 {
+            i += 1;
+        } else {
+            i * 10
+        } finally { runs += 1; });
+        assert_eq!((a, b, c, d, runs), (1, 3, Some(4), 50, 4));
+    }
+
+    #[test]
+    fn catch() {
+        fn parse(s: &str) -> i32 {
+            block!('a: {
+                let n: i32 = match s.parse() {
+                    Ok(n) => n,
+                    Err(e) => break 'a Err(e.to_string()),
+                };
+                if n < 0 { break 'a Err(String::from("negative")); }
+                if n == 0 { break 'a Ok(100); }
+                Ok(n * 2)
+            } catch (msg) {
+                msg.len() as i32
+            })
+        }
+        assert_eq!(parse("21"), 42);
+        assert_eq!(parse("0"), 100);
+        assert_eq!(parse("-5"), 8);
+        assert_eq!(parse("x"), "invalid digit found in string".len() as i32);
+
+        // the error can be destructured, and finally still runs last
+        let mut log = Vec::new();
+        let x = block!('a: {
+            log.push("body");
+            if log.len() == 1 { break 'a Err((7, "seven")); }
+            Ok(Some(1))
+        } catch ((n, _name)) {
+            log.push("catch");
+            Some(n + 1)
+        } finally {
+            log.push("finally");
+        });
+        assert_eq!((x, log), (Some(8), vec!["body", "catch", "finally"]));
+    }
+
+    #[test]
+    fn try_block() {
+        #[derive(Debug, PartialEq)]
+        enum Error { Parse, Zero }
+        impl From<::std::num::ParseIntError> for Error {
+            fn from(_: ::std::num::ParseIntError) -> Error { Error::Parse }
+        }
+
+        fn div(a: &str, b: &str) -> Result<i32, Error> {
+            let r: Result<i32, Error> = try_block!('a: {
+                let a: i32 = a.parse()?;
+                let b: i32 = b.parse()?;
+                if b == 0 { Err(Error::Zero)?; }
+                if a == 0 { break 'a 0; }
+                a / b
+            });
+            // the ? didn't return from the function
+            r.map(|x| x + 1)
+        }
+        assert_eq!(div("10", "2"), Ok(6));
+        assert_eq!(div("0", "2"), Ok(1));
+        assert_eq!(div("10", "0"), Err(Error::Zero));
+        assert_eq!(div("10", "x"), Err(Error::Parse));
+
+        let v: Vec<i32> = vec![];
+        let first: Option<i32> = try_block!('a: { *v.first()? + 1 });
+        assert_eq!(first, None);
+    }
+
+    #[test]
+    fn catch_macro() {
+        fn first_odd(v: &[i32]) -> Result<i32, String> {
+            let r = catch!('a: {
+                for &n in v {
+                    if n < 0 { break 'a Err(format!("negative: {}", n)); }
+                    if n % 2 == 1 { break 'a Ok(n); }
+                }
+                // return still works
+                if v.is_empty() { return Err(String::from("empty")); }
+                0
+            });
+            r.map(|n| n * 10)
+        }
+        assert_eq!(first_odd(&[2, 3, 4]), Ok(30));
+        assert_eq!(first_odd(&[2, -3, 4]), Err(String::from("negative: -3")));
+        assert_eq!(first_odd(&[2, 4]), Ok(0));
+        assert_eq!(first_odd(&[]), Err(String::from("empty")));
+    }
+
+    #[test]
+    fn exit_handlers() {
+        // the break and the end of the body can have different types
+        fn describe(v: &[i32]) -> String {
+            block!('a: {
+                for &n in v {
+                    if n < 0 { break 'a n; }
+                }
+                v.len()
+            } on 'a (n) => format!("negative {}", n),
+              on fallthrough (len) => format!("{} ok", len))
+        }
+        assert_eq!(describe(&[1, -2, 3]), "negative -2");
+        assert_eq!(describe(&[1, 2, 3]), "3 ok");
+
+        // a missing handler passes the value through
+        let f = |x: i32| block!('a: {
+            if x > 10 { break 'a x; }
+            x
+        } on fallthrough (x) => x * 100,);
+        assert_eq!((f(20), f(2)), (20, 200));
+
+        // breaks from a nested block are wrapped too
+        let g = |x: i32| block!('a: {
+            let y = block!('b: {
+                if x == 0 { break 'a 0; }
+                if x < 0 { break 'b -x; }
+                x
+            });
+            y + 1
+        } on 'a (z) => z - 1);
+        assert_eq!((g(0), g(-5), g(5)), (-1, 6, 6));
+    }
+
+    #[cfg(named_block_control_flow)]
+    #[test]
+    fn control_flow() {
+        use std::ops::ControlFlow;
+
+        // stop summing at the first negative number
+        let sum = [1, 2, -3, 4].iter().try_fold(0, |acc, &n| block!(@controlflow 'a: {
+            if n < 0 { break 'a acc; }
+            acc + n
+        }));
+        assert_eq!(sum, ControlFlow::Break(3));
+
+        let sum = [1, 2, 3].iter().try_fold(0, |acc, &n| block!(@controlflow 'a: {
+            if n < 0 { break 'a acc; }
+            acc + n
+        }));
+        assert_eq!(sum, ControlFlow::Continue(6));
+    }
+
+    #[test]
+    fn option_form() {
+        fn find_pair(v: &[i32], sum: i32) -> Option<(usize, usize)> {
+            block!(@option 'a: {
+                for i in 0..v.len() {
+                    for j in i + 1..v.len() {
+                        if v[i] + v[j] == sum { break 'a (i, j); }
+                    }
+                }
+            })
+        }
+        assert_eq!(find_pair(&[1, 4, 6, 9], 10), Some((0, 3)));
+        assert_eq!(find_pair(&[1, 4, 6, 9], 4), None);
+    }
+
+    #[test]
+    fn early_form() {
+        let cached = [(1, "one"), (2, "two")];
+        let lookup = |k: i32| block!(@early 'a: {
+            for &(key, name) in &cached {
+                if key == k { break 'a String::from(name); }
+            }
+            format!("#{}", k)
+        });
+        assert_eq!(lookup(2), (String::from("two"), true));
+        assert_eq!(lookup(3), (String::from("#3"), false));
+    }
+
+    #[test]
+    #[cfg(feature = "nightly")]
+    fn generator() {
+        use std::ops::{Coroutine, CoroutineState};
+        use std::pin::Pin;
+
+        let mut squares = block!('a: generator move {
+            for i in 1.. {
+                if i * i > 10 { break 'a i; }
+                yield i * i;
+            }
+            0
+        });
+        let mut out = vec![];
+        loop {
+            match Pin::new(&mut squares).resume(()) {
+                CoroutineState::Yielded(n) => out.push(n),
+                CoroutineState::Complete(n) => { out.push(-n); break; }
+            }
+        }
+        assert_eq!(out, [1, 4, 9, -4]);
+    }
+
+    #[cfg(named_block_from_fn)]
+    #[test]
+    fn iter_block() {
+        // yields from a loop inside the body, and ends from a match arm
+        let words = ["a", "", "bc", "stop", "d"];
+        let mut i = 0;
+        let lens: Vec<usize> = iter_block!('w: {
+            'skip: loop {
+                let word = words[i];
+                i += 1;
+                match word {
+                    "stop" => break 'w,
+                    "" => continue 'skip,
+                    _ => yield_item![word.len()],
+                }
+            }
+        }).collect();
+        assert_eq!(lens, [1, 2]);
+
+        // falling off the end also stops it
+        let mut n = 0;
+        let evens: Vec<i32> = iter_block!('e: {
+            n += 1;
+            if n % 2 == 0 { yield_item!(n); }
+            if n < 6 { yield_item!(-n); }
+        }).collect();
+        assert_eq!(evens, [-1, 2, -3, 4, -5, 6]);
+
+        // a move iterator returned from a function
+        fn countdown(from: u32) -> impl Iterator<Item = u32> {
+            let mut left = from;
+            iter_block!('c: move {
+                if left == 0 { break 'c }
+                left -= 1;
+                yield_item!(left + 1)
+            })
+        }
+        assert_eq!(countdown(3).collect::<Vec<_>>(), [3, 2, 1]);
+        assert_eq!(countdown(0).count(), 0);
+    }
+
+    #[cfg(all(feature = "std", named_block_exit_code))]
+    #[test]
+    fn main_block() {
+        use std::process::ExitCode;
+
+        struct Guard<'a>(&'a mut bool);
+        impl<'a> Drop for Guard<'a> {
+            fn drop(&mut self) { *self.0 = true; }
+        }
+
+        fn run(n: i32, dropped: &mut bool) -> ExitCode {
+            main_block!('main: {
+                let _guard = Guard(dropped);
+                if n < 0 { break 'main 1; }
+                if n > 100 { break 'main 2; }
+            })
+        }
+        let mut dropped = false;
+        assert_eq!(run(-1, &mut dropped), ExitCode::from(1));
+        assert!(dropped);
+        assert_eq!(run(500, &mut false), ExitCode::from(2));
+        assert_eq!(run(5, &mut false), ExitCode::SUCCESS);
+    }
+
+    #[test]
+    fn max_iter() {
+        // the loop finishes within the limit
+        let mut n = 27u32;
+        let steps = block!('a: loop (max_iter = 1000) {
+            if n == 1 { break 'a "converged"; }
+            n = match n % 2 { 0 => n / 2, _ => 3 * n + 1 };
+        } else {
+            "gave up"
+        });
+        assert_eq!(steps, "converged");
+
+        // it doesn't, so the else arm supplies the value
+        let mut runs = 0;
+        let r = block!('a: loop (max_iter = 5) {
+            runs += 1;
+            if runs % 2 == 0 { continue 'a; }
+        } else {
+            runs * 10
+        });
+        assert_eq!(r, 50);
+
+        // the limit is evaluated once, before the loop starts
+        let mut lookups = 0;
+        let mut limit = || { lookups += 1; 4 };
+        let r = block!('a: loop (max_iter = limit()) {} else { "done" });
+        assert_eq!(r, "done");
+        assert_eq!(lookups, 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn timeout() {
+        use std::time::{Duration, Instant};
+
+        // polls until the deadline
+        let start = Instant::now();
+        let mut polls = 0;
+        let r: Result<(), i32> = block!('a: loop (timeout = Duration::from_millis(20)) {
+            polls += 1;
+            std::thread::sleep(Duration::from_millis(1));
+        } else {
+            Err(polls)
+        });
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(matches!(r, Err(n) if n > 1));
+
+        // a break beats the deadline
+        let r: Result<i32, ()> = block!('a: loop (timeout = Duration::from_secs(60)) {
+            break 'a Ok(1);
+        } else {
+            Err(())
+        });
+        assert_eq!(r, Ok(1));
+
+        // so is the duration
+        let mut lookups = 0;
+        let mut deadline = || { lookups += 1; Duration::from_millis(5) };
+        let r = block!('a: loop (timeout = deadline()) {} else { "done" });
+        assert_eq!(r, "done");
+        assert_eq!(lookups, 1);
+
+        // with both limits, whichever comes first
+        let mut runs = 0;
+        let r = block!('a: loop (max_iter = 3, timeout = Duration::from_secs(60)) {
+            runs += 1;
+        } else {
+            runs
+        });
+        assert_eq!(r, 3);
+    }
+
+    #[test]
+    fn every() {
+        let mut reports = vec![];
+        let mut n = 0;
+        let r = block!('a: loop (every = 4 => |i| reports.push(i)) {
+            n += 1;
+            if n == 10 { break 'a n * 2; }
+        });
+        assert_eq!((r, reports), (20, vec![4, 8]));
+
+        // with a limit, and a function instead of a closure
+        fn check(i: u32) { assert!(i <= 6); }
+        let mut runs = 0;
+        let r = block!('a: loop (every = 2 => check, max_iter = 6) {
+            runs += 1;
+            if runs % 3 == 0 { continue 'a; }
+        } else {
+            runs
+        });
+        assert_eq!(r, 6);
+
+        // every iteration, with the count evaluated once
+        let mut reports = vec![];
+        let mut lookups = 0;
+        let mut count = || { lookups += 1; 1 };
+        block!('a: loop (max_iter = 3, every = count() => |i| reports.push(i)) {} else {});
+        assert_eq!((reports, lookups), (vec![1, 2, 3], 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "loop 'a can't report every 0 iterations")]
+    fn every_zero_panic() {
+        let n = 0;
+        block!('a: loop (every = n => |_| ()) { break 'a; });
+    }
+
+    #[test]
+    fn yield_option() {
+        use std::cell::Cell;
+
+        let yields = Cell::new(0);
+        let mut n = 0;
+        let r = block!('a: loop (yield = yields.set(yields.get() + 1), max_iter = 100) {
+            n += 1;
+            if n < 5 { continue 'a; }
+            break 'a n;
+        } else {
+            0
+        });
+        assert_eq!((r, yields.get()), (5, 5));
+
+        let r = block!('a: loop (yield = core::hint::spin_loop()) {
+            n -= 1;
+            if n == 0 { break 'a "done"; }
+        });
+        assert_eq!(r, "done");
+    }
+
+    #[test]
+    #[should_panic(expected = "loop 'a ran for more than 3 iterations")]
+    fn max_iter_panic() {
+        let _: () = block!('a: loop (max_iter = 3) {});
+    }
+
+    #[test]
+    fn ensure() {
+        let check = |s: &str| block!('a: {
+            ensure!('a, !s.is_empty(), Err("empty"));
+            ensure!('a, s.len() <= 3, Err("too long"),);
+            ensure!('a, s.chars().all(|c| c.is_ascii_digit()), { Err("not a number") });
+            Ok(s.len())
+        });
+        assert_eq!(check("12"), Ok(2));
+        assert_eq!(check(""), Err("empty"));
+        assert_eq!(check("1234"), Err("too long"));
+        assert_eq!(check("1a"), Err("not a number"));
+        let mut n = 0;
+        block!('a: loop {
+            n += 1;
+            ensure!('a, n < 3);
+        });
+        assert_eq!(n, 3);
+        // under an alias
+        let halve = |x: i32| block!('a | 'b: {
+            ensure!('b, x % 2 == 0, None);
+            Some(x / 2)
+        });
+        assert_eq!(halve(4), Some(2));
+        assert_eq!(halve(3), None);
+    }
+
+    #[test]
+    fn break_if() {
+        let classify = |x: i32| block!('a: {
+            break_if!('a, x < 0, "negative");
+            break_if!('a, x == 0, "zero",);
+            break_if!('a, match x { 1 => true, _ => false }, { "one" });
+            "many"
+        });
+        assert_eq!(classify(-1), "negative");
+        assert_eq!(classify(0), "zero");
+        assert_eq!(classify(1), "one");
+        assert_eq!(classify(5), "many");
+        let r = block!('a: for i in 0.. {
+            break_if!('a, i * i > 50, i);
+        });
+        assert_eq!(r, Some(8));
+        let mut n = 0;
+        block!('a: loop {
+            n += 1;
+            break_if!('a, n == 3);
+        });
+        assert_eq!(n, 3);
+        // under an alias
+        let sign = |x: i32| block!('a | 'b: {
+            break_if!('b, x < 0, -1);
+            1
+        });
+        assert_eq!(sign(-5), -1);
+        assert_eq!(sign(5), 1);
+    }
+
+    #[test]
+    fn bail() {
+        let total = |xs: &[i32]| block!('a: {
+            let mut total = 0;
+            for &x in xs {
+                if x < 0 { bail!('a); }
+                total += x;
+            }
+            total
+        });
+        assert_eq!(total(&[1, 2]), 3);
+        assert_eq!(total(&[1, -2]), 0);
+        let name: String = block!('a: {
+            match 1 {
+                0 => "zero".to_string(),
+                _ => bail!('a),
+            }
+        });
+        assert_eq!(name, "");
+        let r = block!('a: for &x in &[1, 2] {
+            if x == 2 { bail!('a,); }
+        });
+        assert_eq!(r, Some(0));
+        // in code pasted from a block labeled 'x
+        let flag = true;
+        let n = block!('a: {
+            #[block(rename('x => 'a))]
+            {
+                if flag { bail!('x); }
+            }
+            5
+        });
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn ok_or_break() {
+        let sum = |a: &str, b: &str| block!('a: {
+            let a: i32 = ok_or_break!(a.parse(), 'a);
+            let b: i32 = ok_or_break!(b.parse::<i32>(), 'a,);
+            Ok(a + b)
+        });
+        assert_eq!(sum("1", "2"), Ok(3));
+        assert!(sum("x", "2").is_err());
+        // with the error mapped, and handled by the catch arm
+        let sum = |a: &str, b: &str| block!('a: {
+            let a: i32 = ok_or_break!(a.parse(), 'a, |_| "first");
+            let b: i32 = ok_or_break!(b.parse(), 'a, |_| "second");
+            Ok(a + b)
+        } catch (which) {
+            which.len() as i32
+        });
+        assert_eq!(sum("1", "2"), 3);
+        assert_eq!(sum("1", "x"), 6);
+        // under an alias
+        let parse = |a: &str| block!('a | 'b: {
+            let a: i32 = ok_or_break!(a.parse(), 'b);
+            Ok(a)
+        });
+        assert_eq!(parse("4"), Ok(4));
+        assert!(parse("x").is_err());
+    }
+
+    #[test]
+    fn or_break() {
+        let first_even = |xs: &[i32]| block!('a: {
+            let x = or_break!(xs.iter().find(|&&x| x % 2 == 0), 'a, -1);
+            // a block-started default, which is walked
+            let y = or_break!(xs.get(*x as usize), 'a, { if *x > 2 { break 'a -2; } -3 });
+            x + y
+        });
+        assert_eq!(first_even(&[1, 2, 5]), 7);
+        assert_eq!(first_even(&[1, 3]), -1);
+        assert_eq!(first_even(&[4, 1]), -2);
+        assert_eq!(first_even(&[2, 1]), -3);
+        let r = block!('a: for x in &[Some(1), None] {
+            let _ = or_break!(*x, 'a, "none");
+        });
+        assert_eq!(r, Some("none"));
+        let mut n = 0;
+        block!('a: {
+            n += or_break!(Some(1), 'a);
+            n += or_break!(None::<i32>, 'a,);
+        });
+        assert_eq!(n, 1);
+        // under an alias (the label is renamed after the first comma)
+        let x = block!('a | 'b: {
+            let x = or_break!(None::<i32>, 'b, 7);
+            x
+        });
+        assert_eq!(x, 7);
+    }
+
+    #[cfg(named_block_label_break)]
+    #[test]
+    fn guard() {
+        let parse = |s: &str| block!('a: {
+            guard!('a, let Some((k, v)) = s.split_once('=') else Err(0));
+            guard!('a, let Ok(v) = v.parse::<i32>() else Err(1));
+            Ok((k.len(), v))
+        });
+        assert_eq!(parse("ab=5"), Ok((2, 5)));
+        assert_eq!(parse("ab"), Err(0));
+        assert_eq!(parse("ab=x"), Err(1));
+        // in a loop, the value is wrapped like a break's
+        let r = block!('a: for x in &[Some(1), None, Some(3)] {
+            guard!('a, let Some(_) = *x else "missing");
+        });
+        assert_eq!(r, Some("missing"));
+        let mut seen = 0;
+        block!('a: {
+            guard!('a, let Some(n) = None::<i32> else);
+            seen = n;
+        });
+        assert_eq!(seen, 0);
+        let r = block!('a: {
+            #[block(rename('x => 'a))]
+            {
+                guard!('x, let [first, ..] = [7, 8][..] else 0);
+                break 'x first;
+            }
+        });
+        assert_eq!(r, 7);
+    }
+
+    #[test]
+    fn block_explicit() {
+        macro_rules! check {
+            ($label:lifetime, $x:expr) => {
+                if $x > 10 { exit!($label, $x * 2); }
+            };
+        }
+        let f = |x: i32| block_explicit!('a: {
+            check!('a, x);
+            if x < 0 { exit!('a, 0); }
+            // left as it is
+            let mut n = 0;
+            let n = 'b: loop {
+                n += 1;
+                if n == 2 { break 'b n; }
+            };
+            x + n
+        });
+        assert_eq!(f(3), 5);
+        assert_eq!(f(11), 22);
+        assert_eq!(f(-5), 0);
+        let mut hit = false;
+        block_explicit!('a: {
+            if !hit { hit = true; exit!('a); }
+            unreachable!();
+        });
+        assert!(hit);
+    }
+
+    #[test]
+    fn break_macro() {
+        let x = 3;
+        let r = block!('a: {
+            if x > 2 { break!('a, { 1 } + 10); }
+            0
+        });
+        assert_eq!(r, 11);
+        let r = block!('a: for i in 0..10 {
+            match i {
+                0 => continue,
+                5 => break!('a, i * 2,),
+                _ => {}
+            }
+            if i > 7 { break!('a, 0); }
+        });
+        assert_eq!(r, Some(10));
+        let mut n = 0;
+        block!('a: {
+            n += 1;
+            if n > 0 { break!('a); }
+            n += 1;
+        });
+        assert_eq!(n, 1);
+        let v: Vec<i32> = block!('a: {
+            vec![1, if x == 3 { break!('a, vec![]) } else { 2 }]
+        });
+        assert!(v.is_empty());
+        let r = block!('outer: {
+            let y = block!('inner: {
+                if x == 3 { break!('outer, "out"); }
+                break!('inner, 5)
+            });
+            let _ = y;
+            "in"
+        });
+        assert_eq!(r, "out");
+        let r = block!('a: {
+            #[block(rename('x => 'a))]
+            {
+                if x == 3 { break!('x, 7); }
+            }
+            0
+        });
+        assert_eq!(r, 7);
+    }
+
+    #[test]
+    fn exit() {
+        // a macro that exits the block it's used in, which the scanner can't see
+        macro_rules! check {
+            ($life:lifetime, $x:expr) => {
+                if $x < 0 { exit!($life, "negative"); }
+            };
+        }
+        fn sign(x: i32) -> &'static str {
+            block!('a: {
+                check!('a, x);
+                if x == 0 { break 'a "zero"; }
+                "positive"
+            })
+        }
+        assert_eq!((sign(-1), sign(0), sign(1)), ("negative", "zero", "positive"));
+
+        macro_rules! stop_at {
+            ($life:lifetime, $x:expr, $limit:expr) => {
+                if $x == $limit { exit!($life); }
+            };
+        }
+        let mut n = 0;
+        block!('a: loop {
+            n += 1;
+            stop_at!('a, n, 5);
+        });
+        assert_eq!(n, 5);
+
+        // the shorthands for breaks fall back to exit! when they come out of a macro
+        macro_rules! first_digit {
+            ($life:lifetime, $s:expr) => {{
+                ensure!($life, !$s.is_empty(), Err("empty"));
+                break_if!($life, $s.len() > 3, Err("too long"));
+                let c = or_break!($s.chars().next(), $life, Err("no first char"));
+                ok_or_break!(c.to_string().parse::<u8>(), $life, |_| "not a digit")
+            }};
+        }
+        let first = |s: &str| block!('a: { Ok(first_digit!('a, s)) });
+        assert_eq!(first("7x"), Ok(7));
+        assert_eq!(first(""), Err("empty"));
+        assert_eq!(first("1234"), Err("too long"));
+        assert_eq!(first("x7"), Err("not a digit"));
+        macro_rules! give_up {
+            ($life:lifetime) => { bail!($life) };
+        }
+        let r: Vec<u8> = block!('a: { give_up!('a); });
+        assert!(r.is_empty());
+        #[cfg(named_block_label_break)]
+        {
+            macro_rules! need {
+                ($life:lifetime, $e:expr) => {
+                    guard!($life, let Some(_) = $e else -1);
+                };
+            }
+            let r = block!('a: { need!('a, None::<i32>); 0 });
+            assert_eq!(r, -1);
+        }
+    }
+
+    #[test]
+    fn retry_form() {
+        let mut tries = 0;
+        let mut log = vec![];
+        let r = block!('a: retry {
+            tries += 1;
+            log.push(tries);
+            match tries {
+                1 => continue 'a,
+                2 => { continue 'a; }
+                3 if log.len() < 3 => break 'a "impossible",
+                _ => {}
+            }
+            "done"
+        });
+        assert_eq!((r, log), ("done", vec![1, 2, 3]));
+
+        // a break still leaves right away
+        let mut n = 10;
+        let r = block!('a: retry {
+            n -= 3;
+            if n < 0 { break 'a n; }
+            continue 'a;
+        });
+        assert_eq!(r, -2);
+    }
+
+    #[test]
+    fn retry() {
+        use super::Exhausted;
+
+        // the first success wins, whether it falls through or breaks
+        let mut tries = 0;
+        let r = retry!('a: attempts = 5, {
+            tries += 1;
+            if tries < 2 { continue 'a; }
+            if tries == 2 { break 'a "early"; }
+            "late"
+        });
+        assert_eq!((r, tries), (Ok("early"), 2));
+
+        // out of attempts
+        let mut tries = 0;
+        let r: Result<i32, Exhausted> = retry!('a: attempts = 3, {
+            tries += 1;
+            if tries > 0 { continue 'a; }
+            tries
+        });
+        assert_eq!((r, tries), (Err(Exhausted), 3));
+
+        // a continue in a match arm
+        let mut tries = 0;
+        let r = retry!('a: attempts = 5, {
+            tries += 1;
+            match tries {
+                1 | 2 => continue 'a,
+                n => n,
+            }
+        });
+        assert_eq!(r, Ok(3));
+
+        // no attempts at all
+        let n = 0;
+        let r = retry!('a: attempts = n, { 1 });
+        assert_eq!(r, Err(Exhausted));
+    }
+
+    #[test]
+    fn nested_loops() {
+        // a Pythagorean triple, skipping the rest of a row with continue 'a
+        let mut tried = 0;
+        let triple = nested_loops!('a for x in 1..20, 'b for y in x..20, 'c while tried < 10000 {
+            tried += 1;
+            if x + y > 25 { continue 'a; }
+            let z = (x * x + y * y) as f64;
+            let r = z.sqrt() as i32;
+            if r * r == x * x + y * y { break 'a (x, y, r); }
+            break 'c;
+        });
+        assert_eq!(triple, Some((3, 4, 5)));
+
+        let none = nested_loops!('a for i in 0..3, 'b for j in 0..3 {
+            if i * j > 10 { break 'a (i, j); }
+        });
+        assert_eq!(none, None);
+    }
+
+    #[test]
+    fn repeat() {
+        let mut runs = 0;
+        let r = repeat!(4, 'a: {
+            runs += 1;
+            if runs == 10 { break 'a runs; }
+        });
+        assert_eq!((r, runs), (None, 4));
+
+        // zero times: straight to the else arm
+        let times = |n: u32| repeat!(n, 'a: {
+            break 'a 1;
+        } else {
+            2
+        });
+        assert_eq!((times(0), times(1)), (2, 1));
+    }
+
+    #[test]
+    fn do_while() {
+        // the body runs once even though the condition is false from the start
+        let mut runs = 0;
+        let r: Option<()> = do_while!('a: {
+            runs += 1;
+        } while runs > 100);
+        assert_eq!((r, runs), (None, 1));
+
+        // continue goes to the condition
+        let mut i = 0;
+        let mut odd = vec![];
+        let r = do_while!('a: {
+            i += 1;
+            if i % 2 == 0 { continue 'a; }
+            if i > 7 { break 'a i; }
+            odd.push(i);
+        } while i < 10);
+        assert_eq!((r, odd), (Some(9), vec![1, 3, 5, 7]));
+    }
+
+    #[test]
+    fn const_block() {
+        // a lookup table built at compile time
+        const fn isqrt(n: u32) -> u32 {
+            const_block!('a: loop (i = 1) {
+                if n < 2 { break 'a n; }
+                if i * i > n { break 'a i - 1; }
+                continue 'a i + 1;
+            })
+        }
+        static ROOTS: [u32; 10] = {
+            let mut table = [0; 10];
+            let mut n = 0;
+            while n < 10 {
+                table[n] = isqrt(n as u32);
+                n += 1;
+            }
+            table
+        };
+        assert_eq!(ROOTS, [0, 1, 1, 1, 2, 2, 2, 2, 2, 3]);
+
+        const SIGN: &str = const_block!('a: {
+            if ROOTS[0] == 0 { break 'a "zero"; }
+            "nonzero"
+        } finally {});
+        assert_eq!(SIGN, "zero");
+    }
+
+    #[test]
+    fn label_aliases() {
+        let pick = |n: i32| block!('a | 'fallback | 'other: {
+            if n == 1 { break 'a "one"; }
+            if n == 2 { break 'fallback "two"; }
+            for i in 0..n {
+                if i == 5 { break 'other "many"; }
+            }
+            "few"
+        });
+        assert_eq!(pick(1), "one");
+        assert_eq!(pick(2), "two");
+        assert_eq!(pick(3), "few");
+        assert_eq!(pick(8), "many");
+
+        // after the forms that start with `@`, and in the break shorthands
+        let find = |v: &[i32]| block!(@option 'a | 'found: {
+            for &x in v { break_if!('found, x > 2, x); }
+        });
+        assert_eq!(find(&[1, 5]), Some(5));
+        assert_eq!(find(&[1]), None);
+        let lookup = |v: Option<i32>| block!(@early 'a | 'missing: {
+            or_break!(v, 'missing, 0)
+        });
+        assert_eq!(lookup(Some(3)), (3, false));
+        assert_eq!(lookup(None), (0, true));
+        let exit = |n: i32| block!(@exit_index 'a | 'b: {
+            if n == 1 { break 'a 10; }
+            if n == 2 { break 'b 20; }
+            0
+        });
+        assert_eq!(exit(1), (10, 1));
+        assert_eq!(exit(2), (20, 2));
+        assert_eq!(exit(3), (0, 0));
+        #[cfg(named_block_control_flow)]
+        {
+            use core::ops::ControlFlow;
+            let flow = |n: i32| block!(@controlflow 'a | 'stop: {
+                if n < 0 { break 'stop n; }
+                n
+            });
+            assert_eq!(flow(-1), ControlFlow::Break(-1));
+            assert_eq!(flow(1), ControlFlow::Continue(1));
+        }
+    }
+
+    #[test]
+    fn nested_labels() {
+        let classify = |v: &[i32]| block!('outer: {
+            let total = 'inner: {
+                let mut total = 0;
+                for &n in v {
+                    if n < 0 { break 'outer String::from("negative"); }
+                    if n == 0 { break 'inner -1; }
+                    total += n;
+                }
+                total
+            };
+            format!("total {}", total)
+        });
+        assert_eq!(classify(&[1, 2, 3]), "total 6");
+        assert_eq!(classify(&[1, 0, -3]), "total -1");
+        assert_eq!(classify(&[1, -2, 0]), "negative");
+    }
+
+    #[test]
+    fn siblings() {
+        let v = [3, -1, 4, -1, 5];
+        let (first_neg, sum, max) = block!('a: {
+            for (i, &n) in v.iter().enumerate() {
+                if n < 0 { break 'a Some(i); }
+            }
+            None
+        }, 'b: {
+            v.iter().sum::<i32>()
+        }, 'c: for &n in &v {
+            if n > 4 { break 'c n; }
+        } else {
+            0
+        });
+        assert_eq!(first_neg, Some(1));
+        assert_eq!(sum, 10);
+        assert_eq!(max, 5);
+    }
+
+    #[test]
+    fn stmt_form() {
+        // (Result is #[must_use])
+        fn checked(n: i32) -> Result<i32, String> {
+            if n < 0 { Err(format!("{} is negative", n)) } else { Ok(n) }
+        }
+
+        let mut log = vec![];
+        for &n in &[1, -2, 3] {
+            block!('a: stmt {
+                if n == 3 { break 'a checked(n); }
+                log.push(n);
+                checked(n)
+            });
+        }
+        assert_eq!(log, [1, -2]);
+    }
+
+    #[test]
+    fn braceless() {
+        let sign = |n: i32| block!('a: if n < 0 {
+            break 'a "negative";
+        } else if n == 0 {
+            "zero"
+        } else {
+            "positive"
+        });
+        assert_eq!(sign(-1), "negative");
+        assert_eq!(sign(0), "zero");
+        assert_eq!(sign(1), "positive");
+
+        let head = |v: &[i32]| block!('a: match v.first() {
+            Some(&n) if n < 0 => break 'a 0,
+            Some(&n) => n,
+            None => -1,
+        });
+        assert_eq!(head(&[-5, 2]), 0);
+        assert_eq!(head(&[5, 2]), 5);
+        assert_eq!(head(&[]), -1);
+    }
+
+    #[test]
+    fn closure_form() {
+        let first_even = block!('f: |v: &[i32]| {
+            for &x in v {
+                if x % 2 == 0 { break 'f Some(x); }
+            }
+            None
+        });
+        assert_eq!(first_even(&[1, 3, 4, 6]), Some(4));
+        assert_eq!(first_even(&[1, 3]), None);
+
+        let limit = 10;
+        let clamp = block!('c: move |x: i32| -> i32 {
+            if x > limit { break 'c limit; }
+            if x < 0 { return 0; }
+            x
+        });
+        assert_eq!(clamp(20), 10);
+        assert_eq!(clamp(-5), 0);
+        assert_eq!(clamp(5), 5);
+
+        let sign = block!('s: |x: i32| if x < 0 { break 's -1 } else if x == 0 { 0 } else { 1 });
+        assert_eq!((sign(-3), sign(0), sign(3)), (-1, 0, 1));
+
+        let mut calls = 0;
+        let mut count = block!('n: || {
+            calls += 1;
+            if calls > 2 { break 'n false; }
+            true
+        });
+        assert_eq!((count(), count(), count()), (true, true, false));
+
+        // inside another block, the closure's label is its own
+        let v: Vec<i32> = block!('outer: {
+            if limit == 0 { break 'outer vec![]; }
+            [1, -2, 3].iter().map(block!('m: |&x: &i32| { if x < 0 { break 'm 0; } x })).collect()
+        });
+        assert_eq!(v, [1, 0, 3]);
+    }
+
+    #[test]
+    fn if_form() {
+        fn classify(v: &[i32]) -> &'static str {
+            block!('a: if v.is_empty() {
+                "empty"
+            } else if v.len() == 1 {
+                if v[0] == 0 { break 'a "zero"; }
+                "single"
+            } else {
+                for &x in v {
+                    if x < 0 { break 'a "has negative"; }
+                }
+                "many"
+            })
+        }
+        assert_eq!(classify(&[]), "empty");
+        assert_eq!(classify(&[0]), "zero");
+        assert_eq!(classify(&[3]), "single");
+        assert_eq!(classify(&[1, -2, 3]), "has negative");
+        assert_eq!(classify(&[1, 2]), "many");
+
+        fn parse(s: &str) -> i32 {
+            block!('a: if let Ok(n) = s.parse::<i32>() {
+                if n < 0 { break 'a 0; }
+                n
+            } else {
+                if s.is_empty() { break 'a -1; }
+                -2
+            })
+        }
+        assert_eq!(parse("7"), 7);
+        assert_eq!(parse("-7"), 0);
+        assert_eq!(parse(""), -1);
+        assert_eq!(parse("x"), -2);
+    }
+
+    #[test]
+    fn match_form() {
+        fn describe(v: Option<i32>) -> &'static str {
+            block!('a: match v {
+                None => break 'a "none",
+                Some(n) if n < 0 => { break 'a "negative"; }
+                Some(0) => if v.is_some() { break 'a "zero" } else { unreachable!() },
+                Some(n) if n > 100 => {
+                    if n > 1000 { break 'a "huge"; }
+                    "big"
+                }
+                Some(1) => "one",
+                _ => break 'a "some"
+            })
+        }
+        assert_eq!(describe(None), "none");
+        assert_eq!(describe(Some(-1)), "negative");
+        assert_eq!(describe(Some(0)), "zero");
+        assert_eq!(describe(Some(5000)), "huge");
+        assert_eq!(describe(Some(500)), "big");
+        assert_eq!(describe(Some(1)), "one");
+        assert_eq!(describe(Some(2)), "some");
+    }
+
+    #[test]
+    fn let_form() {
+        fn swap_if(flag: bool) -> (i32, i32) {
+            block!('a: let (x, y) = {
+                if flag { break 'a (1, 2); }
+                (3, 4)
+            });
+            (y, x)
+        }
+        assert_eq!(swap_if(true), (2, 1));
+        assert_eq!(swap_if(false), (4, 3));
+    }
+
+    #[test]
+    fn result_type() {
+        // neither the break value nor the body says what type they are
+        let bytes = |empty: bool| block!('a: -> Vec<u8> {
+            if empty { break 'a Default::default(); }
+            "abc".into()
+        });
+        assert_eq!(bytes(true), b"");
+        assert_eq!(bytes(false), b"abc");
+    }
+
+    #[test]
+    fn exit_index() {
+        fn classify(n: i32) -> (&'static str, usize) {
+            block!(@exit_index 'a: {
+                if n < 0 { break 'a "negative"; }
+                if n == 0 { break 'a "zero"; }
+                // a break inside the value of another one comes after it
+                if n > 100 {
+                    break 'a {
+                        if n > 1000 { break 'a "huge"; }
+                        "big"
+                    };
+                }
+                "small"
+            })
+        }
+        assert_eq!(classify(-5), ("negative", 1));
+        assert_eq!(classify(0), ("zero", 2));
+        assert_eq!(classify(500), ("big", 3));
+        assert_eq!(classify(5000), ("huge", 4));
+        assert_eq!(classify(7), ("small", 0));
+    }
+
+    #[test]
+    fn for_else() {
+        // the else arm is the value only if the loop ran to completion
+        fn find(v: &[i32], target: i32) -> String {
+            block!('a: for (i, &n) in v.iter().enumerate() {
+                'inner: for _ in 0..n {
+                    // an inner loop doesn't count as the end of the outer one
+                    if n > 100 { break 'inner; }
+                }
+                if n == target { break 'a format!("found at {}", i); }
+            } else {
+                format!("no {}", target)
+            })
+        }
+        assert_eq!(find(&[4, 8, 200, 15], 15), "found at 3");
+        assert_eq!(find(&[4, 8, 200, 15], 16), "no 16");
+        assert_eq!(find(&[], 1), "no 1");
+    }
+
+    #[test]
+    fn while_loops() {
+        // the first multiple of 3 whose square is over 200
+        let mut n = 0;
+        let x = block!('a: while n < 100 {
+            n += 1;
+            if n % 3 != 0 { continue 'a; }
+            if n * n > 200 { break 'a n; }
+        });
+        assert_eq!(x, Some(15));
+
+        // the condition becoming false gives None
+        let mut i = 0;
+        let y = block!('a: while i < 3 {
+            i += 1;
+            if i == 10 { break 'a "ten"; }
+        });
+        assert_eq!((y, i), (None, 3));
+    }
+
+    #[test]
+    fn while_let_loops() {
+        // the pattern is part of the loop header, so it's copied over as it is
+        let mut stack = vec![(1, "one"), (4, "four"), (2, "two")];
+        let x = block!('a: while let Some((n, name)) = stack.pop() {
+            if n % 2 == 1 { continue 'a; }
+            if n > 3 { break 'a name; }
+        });
+        assert_eq!(x, Some("four"));
+
+        // with an `else` arm, that's the value once the pattern stops matching
+        let mut it = [1, 3, 5].iter();
+        let y = block!('a: while let Some(&n) = it.next() {
+            if n % 2 == 0 { break 'a n; }
+        } else {
+            -1
+        });
+        assert_eq!(y, -1);
+
+        let mut i = 0;
+        let z = block!('a: while i < 10 {
+            i += 1;
+            if i * i > 30 { break 'a i * 100; }
+        } else {
+            0
+        });
+        assert_eq!(z, 600);
+    }
+
+    #[test]
+    fn break_across_calls() {
+        for &flag in &[false, true] {
+            let x = block!('b: {
+                let inner = block!('c: {
+                    if flag { break 'b String::from("inner early exit"); }
+                    String::from("inner normal exit")
+                });
+                inner + "!"
+            });
+            assert_eq!(x, if flag { "inner early exit" } else { "inner normal exit!" });
+        }
+
+        let mut i = 0;
+        let y = block!('b: loop {
+            i += 1;
+            block!('c: loop {
+                if i == 3 { break 'b i * 2; }
+                break 'c;
+            });
+        });
+        assert_eq!(y, 6);
+    }
+
+    #[test]
+    fn break_without_value() {
+        let mut log = vec![];
+        for &flag in &[false, true] {
+            block!('a: {
+                if flag { break 'a; }
+                log.push(1);
+            });
+        }
+        let () = block!('a: {
+            log.push(2);
+            break 'a
+        });
+        assert_eq!(log, [1, 2]);
+    }
+
+    #[test]
+    fn cfg_breaks() {
+        #[allow(dead_code)]
+        fn spin() {
+            block!('a: loop {
+                #[cfg(any())]
+                break 'a 5;
+            })
+        }
+
+        let x = block!('a: loop {
+            #[cfg(all())]
+            break 'a 5;
+        });
+        assert_eq!(x, 5);
+    }
+
+    #[test]
+    fn block_values() {
+        for &x in &[0, 1, 2] {
+            let y = block!('a: {
+                if x > 0 { break 'a match x { 1 => 10, _ => 20 }; }
+                0
+            });
+            assert_eq!(y, [0, 10, 20][x]);
+
+            let z = block!('a: {
+                if x > 0 { break 'a if x == 1 { 10 } else { break 'a 20 } }
+                break 'a match x { _ => 0 }
+            });
+            assert_eq!(z, [0, 10, 20][x]);
+        }
+
+        let w = block!('a: {
+            break 'a if true {
+                let v = loop { break 5; };
+                v * 2
+            } else {
+                0
+            };
+        });
+        assert_eq!(w, 10);
+    }
+
+    #[test]
+    fn struct_values() {
+        #[derive(Debug, PartialEq)]
+        struct Pair { a: i32, b: i32 }
+        mod inner {
+            #[derive(Debug, PartialEq)]
+            pub struct Single { pub a: i32 }
+        }
+
+        for &x in &[0, 1, 2] {
+            let p = block!('a: {
+                if x == 1 { break 'a Pair { a: 1, b: if x > 0 { break 'a Pair { a: 2, b: 2 } } else { 0 } }; }
+                if x == 2 { break 'a Pair { a: 3, ..Pair { a: 0, b: 3 } } }
+                Pair { a: 0, b: 0 }
+            });
+            assert_eq!(p, [Pair { a: 0, b: 0 }, Pair { a: 2, b: 2 }, Pair { a: 3, b: 3 }][x]);
+        }
+
+        let s = block!('a: {
+            break 'a inner::Single { a: 5 }
+        });
+        assert_eq!(s, inner::Single { a: 5 });
+    }
+
+    #[test]
+    fn match_arms() {
+        for &x in &[0, 1, 2, 3] {
+            let y = block!('a: {
+                let z = match x {
+                    0 => break 'a 10,
+                    1 => break 'a if x > 0 { 11 } else { 0 },
+                    n => n,
+                };
+                z
+            });
+            assert_eq!(y, [10, 11, 2, 3][x]);
+
+            let mut reached = false;
+            block!('a: {
+                match x {
+                    2 => break 'a,
+                    _ => {}
+                }
+                reached = true;
+            });
+            assert_eq!(reached, x != 2);
+        }
+    }
+
+    #[test]
+    #[allow(unreachable_code, clippy::never_loop, clippy::diverging_sub_expression)]
+    fn expression_positions() {
+        fn id(x: i32) -> i32 { x }
+
+        for &flag in &[false, true] {
+            let v = 'outer: loop {
+                let a = block!('a: {
+                    let x = if flag { id(break 'a 1) } else { 2 };
+                    x
+                });
+                let b = block!('a: {
+                    if !flag { id(break 'outer a * 10); }
+                    id(a + 1)
+                });
+                break b;
+            };
+            assert_eq!(v, if flag { 2 } else { 20 });
+        }
+
+        let w = block!('a: {
+            let _array = [break 'a 4; 3];
+            0
+        });
+        assert_eq!(w, 4);
+    }
+
+    #[test]
+    fn nested_calls() {
         let flag = true;
         let x = block!('a: {
-            if flag { break 'a "early exit"; }
-            "normal exit"
+            let inner = block!('c: {
+                if flag { break 'c 1; }
+                2
+            });
+            let other = crate::block! {'b: {
+                if flag { break 'a inner + 10; }
+                3
+            }};
+            other
         });
-        assert_eq!(x, "early exit");
+        assert_eq!(x, 11);
     }
 
     #[test]
-    fn shadowing() {
-        let flag = false;
-        let x = block!('b: {
-            if flag { break 'b "early exit"; }
-            let _y = block!('c: {
-                if flag { break 'b "inner early exit"; };
-                String::from("inner normal exit")
-            });
+    fn items() {
+        let x = block!('a: {
+            // the macro definitions are copied over without looking inside, so the
+            // templates aren't mistaken for code in the block
+            macro_rules! doubled {
+                ($e:expr) => { 'b: loop { break 'b $e * 2; } };
+            }
+            macro_rules! show {
+                () => { stringify!(break 'a 1) };
+            }
+            break 'a (doubled!(21), show!());
+        });
+        assert_eq!(x, (42, "break 'a 1"));
+
+        // the same goes for items with any kind of visibility
+        let y = block!('a: {
+            pub(crate) fn one() -> &'static str { stringify!(break 'a 1) }
+            pub(super) struct Two;
+            impl Two { fn get(&self) -> &'static str { stringify!(break 'a 2) } }
+            break 'a [one(), Two.get()];
+        });
+        assert_eq!(y, ["break 'a 1", "break 'a 2"]);
+
+        // and for functions with qualifiers
+        let z = block!('a: {
+            const fn one() -> &'static str { stringify!(break 'a 1) }
+            extern "C" fn two() -> usize { stringify!(break 'a 2).len() }
+            unsafe extern "C" fn three() -> usize { stringify!(break 'a 3).len() }
+            break 'a (one(), two(), unsafe { three() });
+        });
+        assert_eq!(z, ("break 'a 1", 10, 10));
+    }
+
+    #[test]
+    fn lifetimes_in_types() {
+        // lifetimes that happen to match the block's label, but are only used in types and
+        // bounds, aren't taken for labels
+        let x = block!('a: {
+            struct Wrap<'a>(&'a str);
+            impl<'a> Wrap<'a> { fn get(&self) -> &'a str { self.0 } }
+            let w: Wrap<'_> = Wrap(pick("one", "two"));
+            let f = |s: &'static str| -> Box<dyn Fn() -> usize + 'static> { Box::new(move || s.len()) };
+            fn pick<'a: 'b, 'b>(x: &'a str, _: &'b str) -> &'b str { x }
+            if f(w.get())() == 3 { break 'a 1; }
+            0
+        });
+        assert_eq!(x, 1);
+    }
 
+    #[test]
+    fn ignore_statements() {
+        // #[block(ignore)] on a `let` covers the whole statement, and on an item the whole item
+        let x = block!('a: {
+            #[block(ignore)]
+            let s = stringify!(break 'a 1);
             #[block(ignore)]
+            fn helper() -> &'static str { stringify!(break 'a 2) }
+            #[block(ignore)]
+            pub struct S(&'static str);
+            let t = #[block(ignore)] (stringify!(break 'a 3)) == "break 'a 3";
+            let u = S(stringify!(break 'a 4)).0 == "break 'a 4";
+            break 'a (s, helper(), t, u);
+        });
+        assert_eq!(x, ("break 'a 1", "break 'a 2", true, false));
+    }
+
+    #[test]
+    fn cfg_attr() {
+        // #[cfg_attr(PRED, block(...))] applies the attribute only when PRED holds
+        let x = block!('a: {
+            #[cfg_attr(test, block(ignore))]
+            let s = stringify!(break 'a 1);
+            #[cfg_attr(not(test), block(ignore))]
+            let t = stringify!(break 'a 2);
+            let u;
+            #[cfg_attr(test, block(ignore))]
             {
-                #[allow(dead_code)]
-                fn f() -> i32 {
-                    block!('b: {
-                        break 'b 42;
-                    })
-                }
+                u = stringify!(break 'a 3);
             }
-            #[allow(dead_code)]
-            fn g() {
-                block!('b: {
-                    break 'b 42;
-                });
+            #[cfg_attr(test, block(opaque))]
+            assert_eq!(stringify!(break 'a 4), "break 'a 4");
+            #[cfg_attr(test, allow(unused_variables))]
+            let w = 5;
+            break 'a [s == "break 'a 1", t == "break 'a 2", u == "break 'a 3"];
+        });
+        assert_eq!(x, [true, false, true]);
+    }
 
-                while false {
-                    break;
-                }
-                while false {
-                    continue;
-                }
-                'b: while false {
-                    continue 'b;
+    #[test]
+    fn rename() {
+        // code pasted from a block labeled 'x works in a block labeled 'a
+        let flag = true;
+        let x = block!('a: {
+            #[block(rename('x => 'a))]
+            {
+                if flag { break 'x 1; }
+                let mut n = 0;
+                'inner: loop {
+                    n += 1;
+                    if n == 5 { break 'inner; }
                 }
+                let _ = n;
             }
+            2
+        });
+        assert_eq!(x, 1);
+    }
 
-            enum Foo { Bar(i32) }
-            let closure = move |Foo::Bar(x): Foo| -> i32 {
-                x + block!('d: {
-                    break 'd 42;
-                })
-            };
-            assert_eq!(closure(Foo::Bar(0)), 42);
+    #[test]
+    fn include() {
+        // #[block(include)] makes the walker treat items and closures as ordinary code
+        let x = block!('a: {
+            #[block(include)]
+            fn helper() -> &'static str { stringify!(break 'a 1) }
+            let f = #[block(include)] move |s: &str| s == stringify!(break 'a 2);
+            let g = |s: &str| s == stringify!(break 'a 3);
+            break 'a (helper() == "break 'a 1", f("break 'a 2"), g("break 'a 3"));
+        });
+        assert_eq!(x, (false, false, true));
+    }
 
-            "normal exit"
+    #[test]
+    fn assembly() {
+        // asm! and global_asm! calls are copied over as they are (a stand-in asm! shows it)
+        macro_rules! asm {
+            ($($t:tt)*) => { stringify!($($t)*) };
+        }
+        let x = block!('a: {
+            let s = asm!("nop", break 'a 1);
+            break 'a s;
         });
-        assert_eq!(x, "normal exit");
+        assert_eq!(x, "\"nop\", break 'a 1");
 
-        'e: for i in 1..5 {
-            assert!(i >= 1 && i < 5);
-            block!('d: {
-                //continue; //~ERROR NoBareContinueInNamedBlock
-                //continue 'd; //~ERROR NoMatchedContinueInNamedBlock
-                continue 'e;
+        #[cfg(target_arch = "x86_64")]
+        {
+            let y = block!('a: {
+                let v: u64;
+                unsafe { ::std::arch::asm!("mov {}, 5", out(reg) v); }
+                break 'a v;
             });
+            assert_eq!(y, 5);
         }
     }
 
     #[test]
-    fn loops() {
-        assert_eq!(42, block!('a: loop { break 'a 42 }));
-        assert_eq!((), block!('a: {})); // make sure it works with no breaks
+    fn macro_calls() {
+        macro_rules! show {
+            ($($t:tt)*) => { stringify!($($t)*) };
+        }
+        let x = block!('a: {
+            // rewritten by default or with #[block(descend)], left alone with #[block(opaque)]
+            let s = show!(break 'a 1);
+            let t = #[block(descend)] show![break 'a 2];
+            let u = #[block(opaque)] show!(break 'a 3);
+            let v = #[block(opaque)] show! { break 'a 4 };
+            break 'a [s == "break 'a 1", t == "break 'a 2", u == "break 'a 3", v == "break 'a 4"];
+        });
+        assert_eq!(x, [false, false, true, true]);
 
-        let mut v = vec![];
-        let mut i = 0;
-        block!('a: loop {
-            i += 1;
-            if i == 5 {
-                continue 'a;
-            } else if i == 10 {
-                break 'a;
+        // the same goes for macros named by a path
+        let y = block!('a: {
+            let s = #[block(descend)] std::stringify!(break 'a 1);
+            let t = #[block(opaque)] std::stringify![break 'a 2];
+            let u = #[block(opaque)] ::std::stringify! { break 'a 3 };
+            break 'a [s == "break 'a 1", t == "break 'a 2", u == "break 'a 3"];
+        });
+        assert_eq!(y, [false, true, true]);
+
+        // #[block(opaque)] also works on any other tt, and on a keyword block as a whole
+        unsafe fn id(s: &str) -> &str { s }
+        let z = block!('a: {
+            let v = #[block(opaque)] [stringify!(break 'a 1), stringify!(continue 'a)];
+            let w = #[block(opaque)] unsafe { id(stringify!(break 'a 2)) };
+            break 'a [v[0], v[1], w];
+        });
+        assert_eq!(z, ["break 'a 1", "continue 'a", "break 'a 2"]);
+    }
+
+    #[test]
+    #[allow(unreachable_code, clippy::diverging_sub_expression)]
+    fn std_macros() {
+        use std::fmt::Write;
+
+        // breaks in the arguments of the usual std macros have to type-check like the
+        // original `break`, including where the format machinery expects a Display value
+        let o = Some(3);
+        let a = block!('a: { assert!(o.is_none(), "{}", break 'a 1); 0 });
+        let b = block!('a: { let _ = matches!(o, Some(x) if x == 3 && break 'a 2); 0 });
+        let c = block!('a: { dbg!(break 'a 3); 0 });
+        let d = block!('a: { let _v: Vec<i32> = vec![break 'a 4, 2]; 0 });
+        let mut s = String::new();
+        let e = block!('a: { let _ = write!(s, "{}", break 'a 5); 0 });
+        let f = block!('a: { let _ = format!("{:?}", match o { Some(x) => break 'a x * 2, None => 0 }); 0 });
+        assert_eq!([a, b, c, d, e, f], [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn raw_identifiers() {
+        // raw identifiers never match the keywords that the walker looks for
+        #[allow(non_camel_case_types)]
+        struct r#struct { r#fn: i32 }
+
+        let flag = true;
+        let x = block!('a: {
+            let r#fn = 1;
+            let r#impl = r#struct { r#fn: 2 };
+            fn r#match() -> i32 { 3 }
+            let r#break = 4;
+            let r#async = 5;
+            if flag { break 'a r#fn + r#impl.r#fn + r#match() + r#break + r#async; }
+            0
+        });
+        assert_eq!(x, 15);
+    }
+
+    #[test]
+    #[deny(unused_variables)]
+    fn inner_attributes() {
+        let x = block!('a: {
+            #![allow(unused_variables)]
+            let y = 1;
+            break 'a 2;
+        });
+        assert_eq!(x, 2);
+
+        let y = block!('a: loop {
+            #![allow(unused_variables)]
+            let z = 3;
+            break 'a 4;
+        });
+        assert_eq!(y, 4);
+
+        // inner attributes further in are copied over too, wherever Rust allows them
+        let z = block!('a: {
+            mod inner {
+                //! Inner docs.
+                pub fn five() -> i32 { 5 }
             }
-            v.push(i);
+            {
+                #![allow(unused_variables)]
+                let unused = 6;
+            }
+            break 'a inner::five();
         });
-        assert_eq!(&*v, &[1, 2, 3, 4, 6, 7, 8, 9]);
+        assert_eq!(z, 5);
+    }
+
+    #[test]
+    fn closures() {
+        // the closures aren't rewritten, so the inner blocks handle their own breaks
+        let x = block!('a: {
+            let f = move |x: i32| -> i32 {
+                x + block!('a: { break 'a 41; })
+            };
+            let g = |x: i32| block!('a: { break 'a x * 2; });
+            let h = || block!('a: { break 'a 1; });
+            break 'a f(1) + g(1) + h();
+        });
+        assert_eq!(x, 45);
+
+        fn call(x: usize, f: fn(usize) -> usize) -> usize { f(x) }
+        let y = block!('a: {
+            let v = call(1, |x| x + stringify!(break 'a 1).len());
+            let w = match v {
+                11 => |x: usize| x + stringify!(break 'a 2).len(),
+                _ => |x: usize| x,
+            };
+            break 'a w(v);
+        });
+        assert_eq!(y, 21);
+
+        let one = 10;
+        let v = scan_tt! {
+            (one) => { 1 };
+            in {
+                let f = |x: i32| x + one;
+                let g = move || one;
+                vec![one, f(0), g(), (|| one)(), one | 2]
+            }
+        };
+        assert_eq!(v, [1, 10, 10, 10, 3]);
+    }
+
+    #[test]
+    fn walker() {
+        // a rewriter that turns `two` into `2` (but the walker leaves items alone)
+        macro_rules! twos {
+            (@rewrite $state:tt $paren:tt (two $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+                twos!(@rewrite $state $paren ($($tail)*) -> ($($out)* 2) $stack)
+            };
+            (@rewrite $state:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+                $crate::__named_block_walk!((twos!) $state $paren $input -> $out $stack)
+            };
+            (@skip $state:tt $kind:tt $input:tt ($($next:tt)*)) => { $($next)* };
+            (@done () ($($out:tt)*)) => {{ $($out)* }};
+        }
+
+        let x = twos!(@rewrite () {} (
+            let v = [two, (two)];
+            fn f(two: i32) -> i32 { two }
+            v[0] + v[1] + f(3)
+        ) -> () ());
+        assert_eq!(x, 7);
+    }
+
+    #[test]
+    fn scan_tt() {
+        let one = 10;
+        let x = scan_tt! {
+            (one) => { 1 };
+            (negate $e:tt) => { -$e };
+            in {
+                #[allow(dead_code)]
+                fn f(one: i32) -> i32 { one }
+                let v = vec![one, (one + one), { negate 5 }];
+                v.iter().sum::<i32>() + #[block(ignore)] (one)
+            }
+        };
+        assert_eq!(x, 8);
+    }
+
+    #[test]
+    fn callback() {
+        macro_rules! pair {
+            (($tag:expr) $e:expr) => { ($tag, $e) }
+        }
+
+        let flag = true;
+        assert_eq!(("bare", 1), block!(@callback (pair!) ("bare") 'a: {
+            if flag { break 'a 1; }
+            2
+        }));
+        assert_eq!(("loop", 3), block!(@callback (pair!) ("loop") 'a: loop {
+            break 'a 3;
+        }));
     }
 }
 
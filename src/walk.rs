@@ -0,0 +1,694 @@
+// The generic tree walker.
+//
+// This knows how to walk through a body of code token by token, descending into token trees,
+// skipping over items, closures, async blocks and #[block(ignore)]/#[block(opaque)] tts, and
+// putting everything back together again. It doesn't know anything about break statements or
+// labels: at every step, it hands the remaining input to a "rewriter" macro, which gets the chance
+// to transform whatever is at the front. The rewriter has a fall-through rule that passes the
+// arguments unchanged back here, so that the walker can take care of the next token. (The crate's
+// own rewriters are skipped for tokens they never transform, see the fall-through rules below.)
+//
+// Walker arguments (this is also what the rewriter passes back):
+//
+//     __named_block_walk!((REWRITER!) STATE PAREN (INPUT...) -> (OUTPUT...) STACK)
+//
+// - REWRITER: path to the rewriter macro, including the `!`
+// - STATE: a single tt belonging to the rewriter, which the walker passes along untouched
+// - PAREN: the bracket type surrounding the code being walked: {}, () or []
+// - INPUT: code that still needs to be walked
+// - OUTPUT: code that has already been walked
+// - STACK: () at the top level, or (PAREN (INPUT...) -> (OUTPUT...) STACK) for each enclosing
+//   token tree, innermost first
+//
+// Rewriter calls:
+//
+//     REWRITER!(@rewrite STATE PAREN (INPUT...) -> (OUTPUT...) STACK)
+//
+//         Look at the front of INPUT (which may be empty). Either transform some of it, moving it
+//         to OUTPUT, and call @rewrite again, or pass the arguments on to the walker.
+//
+//         A rewriter may also push frames of its own onto STACK, with something other than a
+//         bracket type as PAREN. It then has to pop them itself when INPUT runs out, since the
+//         walker only knows how to close brackets.
+//
+//     REWRITER!(@skip STATE KIND (INPUT...) (NEXT...))
+//
+//         The walker is about to skip over the code at the front of INPUT without walking it.
+//         KIND says what it is: `item` (ending with its first brace group or `;`, which also
+//         covers async blocks) or `closure` (the body, ending at the next `,` or `;`). The
+//         rewriter may inspect the code, and then it has to expand to NEXT, which is the walker
+//         call that does the skipping.
+//
+//     REWRITER!(@done STATE (OUTPUT...))
+//
+//         The whole body has been walked.
+
+/// Implementation detail of `block!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_walk {
+    // transfers an item (this and the other @ rules are checked first, so that the main rules
+    // don't try to parse them)
+    (@item ($($rw:tt)*) $state:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $($rw)* (@skip $state item $input ($crate::__named_block_walk!(@item_now ($($rw)*) $state $paren $input -> $out $stack)))
+    };
+    (@item_now ($($rw:tt)*) $state:tt $paren:tt ($ignore:item $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $ignore) $stack)
+    };
+
+    // checks for `async` (see below), then expands to one of two macro calls: the first if it is,
+    // the second if it isn't
+    (@if_async async ($($yes:tt)*) $no:tt) => {
+        $($yes)*
+    };
+    (@if_async $other:tt $yes:tt ($($no:tt)*)) => {
+        $($no)*
+    };
+
+    // checks the keyword in front of a brace group (see below): `async` blocks are transferred
+    // whole, while `try` blocks (nightly) are walked, since a `break` can leave them
+    (@keyword_block async ($($rw:tt)*) $state:tt $paren:tt ($kw:ident $body:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@skip $state item ($body) ($($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw $body) $stack)))
+    };
+    (@keyword_block try ($($rw:tt)*) $state:tt $paren:tt ($kw:ident $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw) $stack)
+    };
+    (@keyword_block $other:tt ($($rw:tt)*) $state:tt $paren:tt ($kw:ident $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw) $stack)
+    };
+    // same for a brace group marked with #[block(ignore)], except that `try` blocks are skipped too
+    (@ignored_keyword_block async ($($rw:tt)*) $state:tt $paren:tt ($kw:ident $body:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw $body) $stack)
+    };
+    (@ignored_keyword_block try ($($rw:tt)*) $state:tt $paren:tt ($kw:ident $body:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw $body) $stack)
+    };
+    (@ignored_keyword_block $other:tt ($($rw:tt)*) $state:tt $paren:tt ($kw:ident $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw) $stack)
+    };
+
+    // transfers the header of whatever follows #[block(include)] (e.g. `pub fn name`, `move |x|`,
+    // `async`) as it is, then goes back to walking at the first token tree, `;`, `,`, lifetime,
+    // `break` or `continue`, so that the rest is treated as ordinary code
+    (@include ($($rw:tt)*) $state:tt $paren:tt (break $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (break $($tail)*) -> $out $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt (continue $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (continue $($tail)*) -> $out $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt ($life:lifetime $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($life $($tail)*) -> $out $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt (; $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (; $($tail)*) -> $out $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt (, $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (, $($tail)*) -> $out $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt ({ $($inner:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ({ $($inner)* } $($tail)*) -> $out $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt (( $($inner:tt)* ) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (( $($inner)* ) $($tail)*) -> $out $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt ([ $($inner:tt)* ] $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ([ $($inner)* ] $($tail)*) -> $out $stack)
+    };
+    (@include $rw:tt $state:tt $paren:tt ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@include $rw $state $paren ($($tail)*) -> ($($out)* $head) $stack)
+    };
+    (@include ($($rw:tt)*) $state:tt $paren:tt () -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren () -> $out $stack)
+    };
+
+    // transfers a `let` statement marked with #[block(ignore)], up to and including the `;`
+    (@ignore_let ($($rw:tt)*) $state:tt $paren:tt (; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* ;) $stack)
+    };
+    (@ignore_let $rw:tt $state:tt $paren:tt ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@ignore_let $rw $state $paren ($($tail)*) -> ($($out)* $head) $stack)
+    };
+    (@ignore_let ($($rw:tt)*) $state:tt $paren:tt () -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren () -> $out $stack)
+    };
+
+    // transfers the pattern of a `let` (and its type, if any) as it is, then goes back to walking
+    // at the `=`, so that the initializer and the `else` block of a let-else are treated as
+    // ordinary code (a `..=` in a range pattern is a single token, so it doesn't stop this)
+    (@let_pattern ($($rw:tt)*) $state:tt $paren:tt (= $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (= $($tail)*) -> $out $stack)
+    };
+    (@let_pattern ($($rw:tt)*) $state:tt $paren:tt (; $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (; $($tail)*) -> $out $stack)
+    };
+    (@let_pattern $rw:tt $state:tt $paren:tt ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@let_pattern $rw $state $paren ($($tail)*) -> ($($out)* $head) $stack)
+    };
+    (@let_pattern ($($rw:tt)*) $state:tt $paren:tt () -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren () -> $out $stack)
+    };
+
+    // checks whether an identifier starts an item, then expands to one of two macro calls: the
+    // first if it does, the second if it doesn't
+    (@if_item pub ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item use ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item extern ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item mod ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item static ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item const ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item trait ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item impl ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item fn ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item type ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item enum ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item struct ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item macro_rules ($($yes:tt)*) $no:tt) => { $($yes)* };
+    (@if_item $other:tt $yes:tt ($($no:tt)*)) => { $($no)* };
+
+    // collects a statement marked with #[cfg_attr(PRED, block(...))], up to the `;`, then puts it
+    // back twice (see below)
+    (@cfg_attr_stmt $pred:tt $dir:tt ($($stmt:tt)*) ($($rw:tt)*) $state:tt $paren:tt (; $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (#[cfg $pred] #[block $dir] $($stmt)*; #[cfg(not $pred)] $($stmt)*; $($tail)*) -> $out $stack)
+    };
+    (@cfg_attr_stmt $pred:tt $dir:tt ($($stmt:tt)*) $rw:tt $state:tt $paren:tt ($head:tt $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@cfg_attr_stmt $pred $dir ($($stmt)* $head) $rw $state $paren ($($tail)*) -> $out $stack)
+    };
+    (@cfg_attr_stmt $pred:tt $dir:tt ($($stmt:tt)*) ($($rw:tt)*) $state:tt $paren:tt () -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (#[cfg $pred] #[block $dir] $($stmt)* #[cfg(not $pred)] $($stmt)*) -> $out $stack)
+    };
+
+    // transfers closure parameters, up to the closing |
+    (@closure $rw:tt $state:tt $paren:tt (| $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body $rw $state $paren ($($tail)*) -> ($($out)* |) $stack)
+    };
+    (@closure $rw:tt $state:tt $paren:tt ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure $rw $state $paren ($($tail)*) -> ($($out)* $head) $stack)
+    };
+    // (no closing | after all: give up and go back to walking)
+    (@closure ($($rw:tt)*) $state:tt $paren:tt () -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren () -> $out $stack)
+    };
+
+    // transfers a closure body, which is either a return type and a block or an expression
+    (@closure_body ($($rw:tt)*) $state:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $($rw)* (@skip $state closure $input ($crate::__named_block_walk!(@closure_body_now ($($rw)*) $state $paren $input -> $out $stack)))
+    };
+    (@closure_body_now ($($rw:tt)*) $state:tt $paren:tt (-> $ret:ty $body:block $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* -> $ret { $body }) $stack)
+    };
+    (@closure_body_now ($($rw:tt)*) $state:tt $paren:tt ($body:expr) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren () -> ($($out)* $body) $stack)
+    };
+    (@closure_body_now ($($rw:tt)*) $state:tt $paren:tt ($body:expr, $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $body,) $stack)
+    };
+    (@closure_body_now ($($rw:tt)*) $state:tt $paren:tt ($body:expr; $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $body;) $stack)
+    };
+    // (couldn't find the end of the body: go back to walking)
+    (@closure_body_now ($($rw:tt)*) $state:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren $input -> $out $stack)
+    };
+
+    // The next four rules handle the end of the input code -- either the walk
+    // is done, or we need to pop the stack and keep walking. We can tell which
+    // it is by checking the context stack. If it's empty, we hand the output
+    // to the rewriter. Otherwise, we take the current output, surround it by
+    // the brace type, and move up the context stack.
+
+    // no context: we're done!
+    (($($rw:tt)*) $state:tt {} () -> $out:tt ()) => {
+        $($rw)* (@done $state $out)
+    };
+    // pop stack and surround with {}
+    (($($rw:tt)*) $state:tt {} () -> ($($out:tt)*) ($paren:tt $tail:tt -> ($($up:tt)*) $stack:tt)) => {
+        $($rw)* (@rewrite $state $paren $tail -> ($($up)* { $($out)* }) $stack)
+    };
+    // pop stack and surround with ()
+    (($($rw:tt)*) $state:tt () () -> ($($out:tt)*) ($paren:tt $tail:tt -> ($($up:tt)*) $stack:tt)) => {
+        $($rw)* (@rewrite $state $paren $tail -> ($($up)* ( $($out)* )) $stack)
+    };
+    // pop stack and surround with []
+    (($($rw:tt)*) $state:tt [] () -> ($($out:tt)*) ($paren:tt $tail:tt -> ($($up:tt)*) $stack:tt)) => {
+        $($rw)* (@rewrite $state $paren $tail -> ($($up)* [ $($out)* ]) $stack)
+    };
+
+    // tree walker ignores #[block(ignore)] tts and items
+    // #[block(ignore)] attribute is ignored
+    // (`async { ... }` and `try { ... }` count as one tt here, see @ignored_keyword_block)
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(ignore)] $kw:ident { $($body:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@ignored_keyword_block $kw ($($rw)*) $state $paren ($kw { $($body)* } $($tail)*) -> $out $stack)
+    };
+    // (on a `let` statement, everything up to and including the `;` is ignored, see @ignore_let)
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(ignore)] let $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@ignore_let ($($rw)*) $state $paren ($($tail)*) -> ($($out)* let) $stack)
+    };
+    // (on an item, the whole item is skipped as usual, but without @skip)
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(ignore)] $kw:ident $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_item $kw
+            ($crate::__named_block_walk!(@item_now ($($rw)*) $state $paren ($kw $($tail)*) -> ($($out)*) $stack))
+            ($($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw) $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(ignore)] $ignore:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $ignore) $stack)
+    };
+    // #[block(include)] is removed, and the tokens after it are transferred without looking for
+    // items, closures and so on (see @include)
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(include)] $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@include ($($rw)*) $state $paren ($($tail)*) -> $out $stack)
+    };
+    // macro calls are walked like the rest of the code, unless marked with #[block(opaque)], in
+    // which case the arguments are transferred untouched; #[block(descend)] asks for the default
+    // explicitly (and also works on calls that would otherwise be left alone, like asm!)
+    // (the macro name can be a path, with or without a leading ::)
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(opaque)] $($name:ident)::+ ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $($name)::+ ! $args) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(opaque)] :: $($name:ident)::+ ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* :: $($name)::+ ! $args) $stack)
+    };
+    // #[block(opaque)] on anything else leaves the next tt alone, like #[block(ignore)], except
+    // that a keyword block (like `unsafe { ... }`) is left alone as a whole
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(opaque)] $kw:ident { $($body:tt)* } $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $kw { $($body)* }) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(opaque)] $opaque:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $opaque) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(descend)] $($name:ident)::+ ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($args $($tail)*) -> ($($out)* $($name)::+ !) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(descend)] :: $($name:ident)::+ ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($args $($tail)*) -> ($($out)* :: $($name)::+ !) $stack)
+    };
+    // #[block(rename('x => 'y))] on a tt: breaks and continues to 'x inside it are changed to 'y
+    // by a separate walk (see __named_block_rename!), and then the result is walked as usual
+    (($($rw:tt)*) $state:tt $paren:tt (#[block(rename($from:lifetime => $to:lifetime))] $renamed:tt $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_rename!) ($from $to (($($rw)*) $state $paren ($($tail)*) -> $out $stack)) {} ($renamed) -> () ())
+    };
+    // #[cfg_attr(PRED, block(...))]: the code it applies to is put back twice, once with
+    // #[cfg(PRED)] and the block(...) attribute and once with #[cfg(not(PRED))] and without it, so
+    // this only works on statements. That code is a macro call (up to the `;`, unless it uses
+    // braces), a keyword followed by a block (like `unsafe { ... }`), a statement starting with any
+    // other identifier (like `let`, up to the `;`) or a single tt. On an item, the attribute is
+    // dropped, since the walker skips the item anyway.
+    (($($rw:tt)*) $state:tt $paren:tt (#[cfg_attr($pred:meta, block $dir:tt)] $($name:ident)::+ ! { $($args:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (#[cfg($pred)] #[block $dir] $($name)::+ ! { $($args)* } #[cfg(not($pred))] $($name)::+ ! { $($args)* } $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[cfg_attr($pred:meta, block $dir:tt)] $($name:ident)::+ ! $args:tt $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@cfg_attr_stmt ($pred) $dir ($($name)::+ ! $args) ($($rw)*) $state $paren ($($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[cfg_attr($pred:meta, block $dir:tt)] $kw:ident { $($body:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (#[cfg($pred)] #[block $dir] $kw { $($body)* } #[cfg(not($pred))] $kw { $($body)* } $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[cfg_attr($pred:meta, block $dir:tt)] $kw:ident $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@if_item $kw
+            ($crate::__named_block_walk!(($($rw)*) $state $paren ($kw $($tail)*) -> $out $stack))
+            ($crate::__named_block_walk!(@cfg_attr_stmt ($pred) $dir ($kw) ($($rw)*) $state $paren ($($tail)*) -> $out $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (#[cfg_attr($pred:meta, block $dir:tt)] $t:tt $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state $paren (#[cfg($pred)] #[block $dir] $t #[cfg(not($pred))] $t $($tail)*) -> $out $stack)
+    };
+    // other attributes pass through
+    (($($rw:tt)*) $state:tt $paren:tt (#[$attr:meta] $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* #[$attr]) $stack)
+    };
+    // so do inner attributes (including //! comments, which turn into #![doc = "..."])
+    (($($rw:tt)*) $state:tt $paren:tt (#![$attr:meta] $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* #![$attr]) $stack)
+    };
+    // inline assembly is transferred untouched, so that templates and operands aren't rewritten
+    // (also after a path like `core::arch::`, since the path is transferred token by token)
+    (($($rw:tt)*) $state:tt $paren:tt (asm ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* asm ! $args) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (global_asm ! $args:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* global_asm ! $args) $stack)
+    };
+    // `let` (also in `if let` and `while let`): the pattern is left alone, see @let_pattern
+    (($($rw:tt)*) $state:tt $paren:tt (let $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@let_pattern ($($rw)*) $state $paren ($($tail)*) -> ($($out)* let) $stack)
+    };
+    // ignore items: pub(...), pub, use, extern, static, const, unsafe trait/impl/fn, fn, mod, type,
+    // enum, trait, (unsafe) auto trait, impl, struct, macro_rules!, macro, async fn, default
+    // fn/impl (const fn and extern fn are covered by const and extern). Raw identifiers like r#fn
+    // never match these, so they are transferred like any other token.
+    (($($rw:tt)*) $state:tt $paren:tt (pub ($($vis:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (pub ($($vis)*) $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (pub $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (pub $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (use $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (use $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (extern $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (extern $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (mod $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (mod $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (static $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (static $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (const $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (const $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (trait $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (trait $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (unsafe trait $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (unsafe trait $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (auto trait $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (auto trait $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (unsafe auto trait $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (unsafe auto trait $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (impl $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (impl $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (unsafe impl $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (unsafe impl $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (fn $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (fn $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (unsafe fn $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (unsafe fn $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (unsafe extern $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (unsafe extern $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (type $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (type $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (enum $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (enum $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (struct $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (struct $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (macro_rules ! $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (macro_rules ! $($tail)*) -> $out $stack)
+    };
+    // `async fn` (Rust 2018): `async` has to be passed on as it was found in the input, because an
+    // `async` written here would be from Rust 2015, so it is captured and checked by @if_async
+    (($($rw:tt)*) $state:tt $paren:tt ($async:ident fn $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($crate::__named_block_walk!(@item ($($rw)*) $state $paren ($async fn $($tail)*) -> ($($out)*) $stack))
+            ($($rw)* (@rewrite $state $paren (fn $($tail)*) -> ($($out)* $async) $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt ($async:ident unsafe fn $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($crate::__named_block_walk!(@item ($($rw)*) $state $paren ($async unsafe fn $($tail)*) -> ($($out)*) $stack))
+            ($($rw)* (@rewrite $state $paren (unsafe fn $($tail)*) -> ($($out)* $async) $stack)))
+    };
+    // (specialization, nightly only -- `default` on its own can be a variable name)
+    (($($rw:tt)*) $state:tt $paren:tt (default fn $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (default fn $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (default unsafe $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (default unsafe $($tail)*) -> $out $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (default impl $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (default impl $($tail)*) -> $out $stack)
+    };
+    // (macros 2.0, nightly only)
+    (($($rw:tt)*) $state:tt $paren:tt (macro $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@item ($($rw)*) $state $paren (macro $($tail)*) -> $out $stack)
+    };
+
+    // `async` blocks can't break out of the surrounding block either, so they are skipped (checked
+    // by @if_async and @keyword_block, like `async fn`)
+    (($($rw:tt)*) $state:tt $paren:tt ($async:ident move { $($body:tt)* } $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($($rw)* (@skip $state item ({ $($body)* }) ($($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $async move { $($body)* }) $stack))))
+            ($($rw)* (@rewrite $state $paren (move { $($body)* } $($tail)*) -> ($($out)* $async) $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt ($kw:ident { $($body:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(@keyword_block $kw ($($rw)*) $state $paren ($kw { $($body)* } $($tail)*) -> $out $stack)
+    };
+
+    // Closures can't break out of the surrounding block, so the tree walker skips them. A `|` or
+    // `||` is taken to start a closure after `move`, `async`, `=`, `,`, `;`, `=>` or `return`, or
+    // at the beginning of a token tree, since a `|` operator can't come right after any of those.
+    (($($rw:tt)*) $state:tt $paren:tt (move | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* move |) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (move || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* move ||) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (= | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* = |) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (= || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* = ||) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (, | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* , |) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (, || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* , ||) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (; | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* ; |) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (; || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* ; ||) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (=> | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* => |) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (=> || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* => ||) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (return | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* return |) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (return || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* return ||) $stack)
+    };
+    // `async` and `async move` closures (checked by @if_async, like `async fn`)
+    (($($rw:tt)*) $state:tt $paren:tt ($async:ident move | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* $async move |) $stack))
+            ($($rw)* (@rewrite $state $paren (move | $($tail)*) -> ($($out)* $async) $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt ($async:ident move || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* $async move ||) $stack))
+            ($($rw)* (@rewrite $state $paren (move || $($tail)*) -> ($($out)* $async) $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt ($async:ident | $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> ($($out)* $async |) $stack))
+            ($($rw)* (@rewrite $state $paren (| $($tail)*) -> ($($out)* $async) $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt ($async:ident || $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(@if_async $async
+            ($crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> ($($out)* $async ||) $stack))
+            ($($rw)* (@rewrite $state $paren (|| $($tail)*) -> ($($out)* $async) $stack)))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (| $($tail:tt)*) -> () $stack:tt) => {
+        $crate::__named_block_walk!(@closure ($($rw)*) $state $paren ($($tail)*) -> (|) $stack)
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (|| $($tail:tt)*) -> () $stack:tt) => {
+        $crate::__named_block_walk!(@closure_body ($($rw)*) $state $paren ($($tail)*) -> (||) $stack)
+    };
+
+    // tree walker descends into token trees
+    (($($rw:tt)*) $state:tt $paren:tt ({ $($inner:tt)* } $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state {} ($($inner)*) -> () ($paren ($($tail)*) -> $out $stack))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt (( $($inner:tt)* ) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state () ($($inner)*) -> () ($paren ($($tail)*) -> $out $stack))
+    };
+    (($($rw:tt)*) $state:tt $paren:tt ([ $($inner:tt)* ] $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $($rw)* (@rewrite $state [] ($($inner)*) -> () ($paren ($($tail)*) -> $out $stack))
+    };
+
+    // fall-through case for the crate's own rewriters (the ones named by a `$crate::` path): they
+    // only ever look at `break`, `continue`, a lifetime, an attribute, an internal `@` token, a
+    // macro call or `name 'label`, so the walker transfers anything else itself instead of
+    // bouncing every token off the rewriter (which would cost two expansions per token)
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren () -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt break $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren (break $($tail)*) -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt continue $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren (continue $($tail)*) -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt $life:lifetime $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren ($life $($tail)*) -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt # $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren (# $($tail)*) -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt @ $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren (@ $($tail)*) -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt $name:ident ! $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren ($name ! $($tail)*) -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt $name:ident $life:lifetime $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $krate::$rw!(@rewrite $state $paren ($name $life $($tail)*) -> ($($out)* $head) $stack)
+    };
+    (($krate:ident :: $rw:ident !) $state:tt $paren:tt ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_walk!(($krate::$rw!) $state $paren ($($tail)*) -> ($($out)* $head) $stack)
+    };
+
+    // fall-through case for tree walker: transfer over a token
+    (($($rw:tt)*) $state:tt $paren:tt ($head:tt $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $($rw)* (@rewrite $state $paren ($($tail)*) -> ($($out)* $head) $stack)
+    };
+}
+
+/// Implementation detail of `block!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_rename {
+    // Rewriter for #[block(rename('x => 'y))]. STATE is ('x 'y CONTINUATION), where CONTINUATION
+    // holds the arguments of the interrupted walk, which goes on once the tt has been renamed.
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (break $life:lifetime $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $from == $life {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren ($($tail)*) -> ($($out)* break $to) $stack)
+            } else {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren ($($tail)*) -> ($($out)* break $life) $stack)
+            }
+        }
+    };
+    // break!('x, VALUE), guard!('x, ...), bail!('x), break_if!('x, ...) and ensure!('x, ...):
+    // rename the label, then walk the rest
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (break ! ($life:lifetime $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@macro_label break $life ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (guard ! ($life:lifetime $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@macro_label guard $life ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (bail ! ($life:lifetime $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@macro_label bail $life ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (break_if ! ($life:lifetime $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@macro_label break_if $life ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (ensure ! ($life:lifetime $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@macro_label ensure $life ($($args)*) $($tail)*) -> $out $stack)
+    };
+    // or_break!(EXPR, 'x, ...) and ok_or_break!(EXPR, 'x, ...): the label comes after the comma
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (or_break ! ($($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@comma_label or_break () ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (ok_or_break ! ($($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@comma_label ok_or_break () ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (@comma_label $name:ident ($($expr:tt)*) (, $life:lifetime $($args:tt)*) $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $from == $life {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) () ($($expr)* , $to $($args)*) -> () ($paren ($($tail)*) -> ($($out)* $name !) $stack))
+            } else {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) () ($($expr)* , $life $($args)*) -> () ($paren ($($tail)*) -> ($($out)* $name !) $stack))
+            }
+        }
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (@comma_label $name:ident ($($expr:tt)*) ($t:tt $($args:tt)*) $($tail:tt)*) -> $out:tt $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren (@comma_label $name ($($expr)* $t) ($($args)*) $($tail)*) -> $out $stack)
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (@comma_label $name:ident ($($expr:tt)*) () $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_rename!(@rewrite ($from $to $cont) () ($($expr)*) -> () ($paren ($($tail)*) -> ($($out)* $name !) $stack))
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (@macro_label $name:ident $life:tt ($($args:tt)*) $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $from == $life {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) () ($($args)*) -> ($to) ($paren ($($tail)*) -> ($($out)* $name !) $stack))
+            } else {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) () ($($args)*) -> ($life) ($paren ($($tail)*) -> ($($out)* $name !) $stack))
+            }
+        }
+    };
+    (@rewrite ($from:tt $to:tt $cont:tt) $paren:tt (continue $life:lifetime $($tail:tt)*) -> ($($out:tt)*) $stack:tt) => {
+        $crate::__named_block_cond! {
+            if $from == $life {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren ($($tail)*) -> ($($out)* continue $to) $stack)
+            } else {
+                $crate::__named_block_rename!(@rewrite ($from $to $cont) $paren ($($tail)*) -> ($($out)* continue $life) $stack)
+            }
+        }
+    };
+    (@rewrite $state:tt $paren:tt $input:tt -> $out:tt $stack:tt) => {
+        $crate::__named_block_walk!(($crate::__named_block_rename!) $state $paren $input -> $out $stack)
+    };
+    (@skip $state:tt $kind:tt $input:tt ($($next:tt)*)) => {
+        $($next)*
+    };
+    (@done ($from:tt $to:tt (($($rw:tt)*) $state:tt $paren:tt ($($tail:tt)*) -> $out:tt $stack:tt)) ($($renamed:tt)*)) => {
+        $($rw)* (@rewrite $state $paren ($($renamed)* $($tail)*) -> $out $stack)
+    };
+}
+
+/// Rewrites a body of code using your own rules, with the same tree walker that powers `block!`.
+///
+/// Each rule is a pattern and a replacement, written like a `macro_rules!` arm. The walker goes
+/// through the body token by token, descending into `()`, `[]` and `{}` groups. At every
+/// position, the rules are tried in order against the code that comes next: if one matches, the
+/// tokens it matched are replaced (the replacement is not rewritten again) and the walk continues
+/// after them. Like `block!`, the walker skips items (such as nested `fn`s) and any token tree
+/// annotated with `#[block(ignore)]`.
+///
+/// The macro expands to a block expression containing the rewritten body.
+///
+/// Examples
+/// ========
+///
+/// ```
+/// # #[macro_use] extern crate named_block;
+/// # fn main() {
+/// let x = scan_tt! {
+///     (two) => { 2 };
+///     (double $e:tt) => { 2 * $e };
+///     in {
+///         let v = [two, double 3];
+///         v[0] + v[1]
+///     }
+/// };
+/// assert_eq!(x, 8);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! scan_tt {
+    ($(($($pat:tt)*) => { $($rep:tt)* };)* in { $($body:tt)* }) => {
+        $crate::__named_block_scan_tt!(($) ($(($($pat)*) => { $($rep)* })*) $($body)*)
+    };
+}
+
+/// Implementation detail of `scan_tt!`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __named_block_scan_tt {
+    // defines a rewriter from the rules, then starts the walk
+    // ($d is a dollar sign, so that the generated macro can have its own metavariables)
+    (($d:tt) ($(($($pat:tt)*) => { $($rep:tt)* })*) $($body:tt)*) => {{
+        macro_rules! __named_block_scan_tt_rewriter {
+            $(
+                (@rewrite $d state:tt $d paren:tt ($($pat)* $d ($d tail:tt)*) -> ($d ($d out:tt)*) $d stack:tt) => {
+                    __named_block_scan_tt_rewriter!(@rewrite $d state $d paren ($d ($d tail)*) -> ($d ($d out)* $($rep)*) $d stack)
+                };
+            )*
+            (@rewrite $d state:tt $d paren:tt $d input:tt -> $d out:tt $d stack:tt) => {
+                $crate::__named_block_walk!((__named_block_scan_tt_rewriter!) $d state $d paren $d input -> $d out $d stack)
+            };
+            (@skip $d state:tt $d kind:tt $d input:tt ($d ($d next:tt)*)) => {
+                $d ($d next)*
+            };
+            (@done () ($d ($d out:tt)*)) => {{
+                $d ($d out)*
+            }};
+        }
+
+        __named_block_scan_tt_rewriter!(@rewrite () {} ($($body)*) -> () ())
+    }};
+}